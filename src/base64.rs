@@ -0,0 +1,176 @@
+// base64.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+// always compiled, since attachments are decoded with it, but only public with the feature
+#![cfg_attr(not(feature = "base64"), allow(dead_code))]
+
+use std::io::{self, BufRead, Read};
+
+/// Decodes a base64 body (RFC 4648) as it is read, from
+/// [`MboxEntry::base64_body_reader`](crate::MboxEntry::base64_body_reader).
+///
+/// Line breaks and anything else outside the alphabet are skipped, and decoding stops at the
+/// first `=`. A trailing partial group of one character is ignored.
+pub struct Base64Reader<R> {
+    inner: R,
+    /// When clear, the body isn't encoded and is passed through unchanged.
+    decode: bool,
+    decoder: Decoder,
+    /// Decoded bytes, and how many of them have been returned.
+    decoded: Vec<u8>,
+    pos: usize,
+    /// Set once the end of the input has been reached and the last group decoded.
+    finished: bool,
+}
+
+impl<R: BufRead> Base64Reader<R> {
+    pub(crate) fn new(inner: R, decode: bool) -> Self {
+        Base64Reader {
+            inner,
+            decode,
+            decoder: Decoder::default(),
+            decoded: Vec::new(),
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: BufRead> Read for Base64Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.decode {
+            return self.inner.read(buf);
+        }
+        while self.pos == self.decoded.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.decoded.clear();
+            self.pos = 0;
+            let encoded = self.inner.fill_buf()?;
+            if encoded.is_empty() {
+                self.decoder.finish(&mut self.decoded);
+                self.finished = true;
+                continue;
+            }
+            self.decoder.feed(encoded, &mut self.decoded);
+            let len = encoded.len();
+            self.inner.consume(len);
+        }
+        let copied = (self.decoded.len() - self.pos).min(buf.len());
+        buf[..copied].copy_from_slice(&self.decoded[self.pos..self.pos + copied]);
+        self.pos += copied;
+        Ok(copied)
+    }
+}
+
+/// Decodes base64 given in pieces, keeping the characters of a group split between them.
+#[derive(Default)]
+struct Decoder {
+    group: u32,
+    count: u8,
+    /// Set once an `=` has been seen, after which everything is ignored.
+    ended: bool,
+}
+
+impl Decoder {
+    fn feed(&mut self, encoded: &[u8], out: &mut Vec<u8>) {
+        if self.ended {
+            return;
+        }
+        for &b in encoded {
+            let value = match b {
+                b'A'..=b'Z' => b - b'A',
+                b'a'..=b'z' => b - b'a' + 26,
+                b'0'..=b'9' => b - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                b'=' => {
+                    self.ended = true;
+                    break;
+                }
+                _ => continue,
+            };
+            self.group = self.group << 6 | u32::from(value);
+            self.count += 1;
+            if self.count == 4 {
+                out.extend_from_slice(&self.group.to_be_bytes()[1..]);
+                self.group = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Decodes what is left of the last group.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        match self.count {
+            2 => out.push((self.group >> 4) as u8),
+            3 => out.extend_from_slice(&((self.group >> 2) as u16).to_be_bytes()),
+            _ => {}
+        }
+        self.group = 0;
+        self.count = 0;
+    }
+}
+
+/// Decodes base64 (RFC 4648) held in memory all at once, like [`Base64Reader`].
+pub(crate) fn decode_base64(encoded: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut decoder = Decoder::default();
+    decoder.feed(encoded, &mut decoded);
+    decoder.finish(&mut decoded);
+    decoded
+}
+
+#[cfg(all(test, feature = "base64"))]
+mod test {
+    use crate::MboxReader;
+    use std::io::{self, Read};
+
+    #[test]
+    fn base64_body_reader() -> io::Result<()> {
+        let input = concat!(
+            "From a\n",
+            "Content-Transfer-Encoding: BASE64\n",
+            "\n",
+            "SGVsbG8sIHdvcmxkIQpUaGlzIGlzIGEgbG9uZ2VyIHBheWxvYWQsIHNwbGl0IG92ZXIgbGluZXMu\n",
+            "\r\n",
+            "AAH/\n",
+            "\n",
+            "From b\n",
+            "Subject: not encoded\n",
+            "\n",
+            "SGk=\n",
+        );
+        let mut expected = b"Hello, world!\nThis is a longer payload, split over lines.".to_vec();
+        expected.extend_from_slice(&[0x00, 0x01, 0xFF]);
+        // a small buffer, so groups are split between reads of the body
+        for capacity in [6, 7, 16, 8192] {
+            let mut reader = MboxReader::with_capacity(capacity, input.as_bytes());
+            let mut body = Vec::new();
+            let mut decoded = reader.next()?.unwrap().base64_body_reader()?;
+            let mut buf = [0; 5];
+            loop {
+                match decoded.read(&mut buf)? {
+                    0 => break,
+                    n => body.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(body, expected, "capacity {}", capacity);
+
+            let mut body = Vec::new();
+            reader
+                .next()?
+                .unwrap()
+                .base64_body_reader()?
+                .read_to_end(&mut body)?;
+            assert_eq!(body, b"SGk=\n");
+        }
+        Ok(())
+    }
+}
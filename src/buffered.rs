@@ -0,0 +1,198 @@
+// buffered.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::MboxReader;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Reads messages into a fixed set of reusable buffers, for a pipeline where one message is
+/// processed (e.g. on another thread) while the next is read. Each message is handed out in a
+/// buffer of its own, which goes back to the set when the consumer is done with it, through
+/// [`BufferedMessages::give_back`] or a [`Recycler`]. No more buffers than the set holds are ever
+/// allocated, and ones which have been given back keep their capacity for the next message.
+pub struct BufferedMessages<R> {
+    reader: MboxReader<R>,
+    pool: Arc<(Mutex<Pool>, Condvar)>,
+}
+
+struct Pool {
+    free: Vec<Vec<u8>>,
+    /// How many `Recycler`s there are, since while there are none nothing will be given back
+    /// except by the `BufferedMessages` itself.
+    recyclers: usize,
+}
+
+fn lock(pool: &Mutex<Pool>) -> MutexGuard<'_, Pool> {
+    // the pool is only ever pushed to and popped from, so it's fine after a panic
+    pool.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<R: Read> BufferedMessages<R> {
+    /// Reads messages from `reader` into a set of `buffers` buffers, which must be at least one.
+    pub fn new(reader: MboxReader<R>, buffers: usize) -> Self {
+        assert!(buffers > 0);
+        let pool = Pool {
+            free: vec![Vec::new(); buffers],
+            recyclers: 0,
+        };
+        BufferedMessages {
+            reader,
+            pool: Arc::new((Mutex::new(pool), Condvar::new())),
+        }
+    }
+
+    /// Reads the next message into a free buffer and hands it out, or returns `None` at the end
+    /// of the stream. If every buffer is out, this waits for one to be given back through a
+    /// [`Recycler`]; if there are no recyclers, an `InvalidInput` error is returned instead, since
+    /// none ever would be.
+    pub fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = self.take_free()?;
+        buf.clear();
+        let read = match self.reader.next() {
+            Ok(Some(mut entry)) => entry.read_to_end(&mut buf).map(|_| true),
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        };
+        match read {
+            Ok(true) => Ok(Some(buf)),
+            other => {
+                self.give_back(buf);
+                other.map(|_| None)
+            }
+        }
+    }
+
+    fn take_free(&self) -> io::Result<Vec<u8>> {
+        let (pool, returned) = &*self.pool;
+        let mut pool = lock(pool);
+        loop {
+            if let Some(buf) = pool.free.pop() {
+                return Ok(buf);
+            }
+            if pool.recyclers == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "every buffer is in use",
+                ));
+            }
+            pool = returned.wait(pool).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Returns a buffer which was handed out by `next_message`, so it can be reused.
+    pub fn give_back(&self, buf: Vec<u8>) {
+        give_back(&self.pool, buf);
+    }
+
+    /// Returns a handle for giving buffers back from elsewhere, e.g. from the thread processing
+    /// the messages.
+    pub fn recycler(&self) -> Recycler {
+        lock(&self.pool.0).recyclers += 1;
+        Recycler {
+            pool: Arc::clone(&self.pool),
+        }
+    }
+
+    pub fn into_inner(self) -> MboxReader<R> {
+        self.reader
+    }
+}
+
+fn give_back(pool: &(Mutex<Pool>, Condvar), buf: Vec<u8>) {
+    let (pool, returned) = pool;
+    lock(pool).free.push(buf);
+    returned.notify_one();
+}
+
+/// Gives buffers back to a [`BufferedMessages`] from wherever its messages are processed. It can
+/// be cloned and sent to other threads.
+pub struct Recycler {
+    pool: Arc<(Mutex<Pool>, Condvar)>,
+}
+
+impl Recycler {
+    /// Returns a buffer which was handed out by [`BufferedMessages::next_message`], so it can be
+    /// reused.
+    pub fn give_back(&self, buf: Vec<u8>) {
+        give_back(&self.pool, buf);
+    }
+}
+
+impl Clone for Recycler {
+    fn clone(&self) -> Self {
+        lock(&self.pool.0).recyclers += 1;
+        Recycler {
+            pool: Arc::clone(&self.pool),
+        }
+    }
+}
+
+impl Drop for Recycler {
+    fn drop(&mut self) {
+        let (pool, returned) = &*self.pool;
+        lock(pool).recyclers -= 1;
+        // a reader waiting for a buffer may now be waiting for nothing
+        returned.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn input() -> Vec<u8> {
+        (0..6)
+            .map(|i| format!("From {0}\n\nmessage {0}\n", i))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn ring_of_two() -> io::Result<()> {
+        let input = input();
+        let mut messages = BufferedMessages::new(MboxReader::new(input.as_slice()), 2);
+        let first = messages.next_message()?.unwrap();
+        let second = messages.next_message()?.unwrap();
+        assert_eq!(first, b"From 0\n\nmessage 0\n");
+        assert_eq!(second, b"From 1\n\nmessage 1\n");
+        let error = messages.next_message().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // the buffer given back is the one reused
+        let reused = first.as_ptr();
+        messages.give_back(first);
+        let third = messages.next_message()?.unwrap();
+        assert_eq!(third, b"From 2\n\nmessage 2\n");
+        assert_eq!(third.as_ptr(), reused);
+        messages.give_back(second);
+        messages.give_back(third);
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline() -> io::Result<()> {
+        let input = input();
+        let mut messages = BufferedMessages::new(MboxReader::new(input.as_slice()), 2);
+        let recycler = messages.recycler();
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let consumer = std::thread::spawn(move || {
+            let mut seen = Vec::new();
+            for message in receiver {
+                seen.push(String::from_utf8(message.clone()).unwrap());
+                recycler.give_back(message);
+            }
+            seen
+        });
+        while let Some(message) = messages.next_message()? {
+            sender.send(message).unwrap();
+        }
+        drop(sender);
+        let seen = consumer.join().unwrap();
+        assert_eq!(seen.len(), 6);
+        assert_eq!(seen[5], "From 5\n\nmessage 5\n");
+        assert_eq!(seen.concat().as_bytes(), input);
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+// cache.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::MboxReader;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek};
+
+/// Keeps the most recently read messages in memory, keyed by their offsets, for callers which
+/// revisit the same messages (e.g. a UI moving back and forth through a list).
+pub struct CachedMboxReader<R> {
+    reader: MboxReader<R>,
+    capacity: usize,
+    /// Least recently used first. Linear search is fine, since the cache is meant to be small.
+    entries: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> CachedMboxReader<R> {
+    /// Caches up to `capacity` messages read from `reader`.
+    pub fn new(reader: MboxReader<R>, capacity: usize) -> Self {
+        CachedMboxReader {
+            reader,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the message starting at `offset`, from the cache if possible and otherwise by
+    /// [`MboxReader::read_message_at`].
+    pub fn get_message(&mut self, offset: u64) -> io::Result<Vec<u8>> {
+        if let Some(i) = self.entries.iter().position(|(o, _)| *o == offset) {
+            let entry = self.entries.remove(i).unwrap();
+            let msg = entry.1.clone();
+            self.entries.push_back(entry);
+            return Ok(msg);
+        }
+        let msg = self.reader.read_message_at(offset)?;
+        if self.capacity > 0 {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+            }
+            self.entries.push_back((offset, msg.clone()));
+        }
+        Ok(msg)
+    }
+
+    pub fn into_inner(self) -> MboxReader<R> {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::{Cursor, SeekFrom};
+    use std::rc::Rc;
+
+    struct CountingReader {
+        inner: Cursor<&'static [u8]>,
+        reads: Rc<Cell<usize>>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for CountingReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn cache_hits() -> io::Result<()> {
+        let reads = Rc::new(Cell::new(0));
+        let inner = CountingReader {
+            inner: Cursor::new(b"From test1\ntest1\nFrom test2\ntest2\nFrom test3\ntest3\n"),
+            reads: reads.clone(),
+        };
+        let mut cache = CachedMboxReader::new(MboxReader::new(inner), 2);
+
+        assert_eq!(cache.get_message(17)?, b"From test2\ntest2\n");
+        let after_first = reads.get();
+        assert!(after_first > 0);
+        assert_eq!(cache.get_message(17)?, b"From test2\ntest2\n");
+        assert_eq!(reads.get(), after_first);
+
+        // fill the cache so the least recently used entry is evicted
+        assert_eq!(cache.get_message(0)?, b"From test1\ntest1\n");
+        assert_eq!(cache.get_message(17)?, b"From test2\ntest2\n");
+        assert_eq!(cache.get_message(34)?, b"From test3\ntest3\n");
+        let before = reads.get();
+        assert_eq!(cache.get_message(17)?, b"From test2\ntest2\n");
+        assert_eq!(reads.get(), before);
+        assert_eq!(cache.get_message(0)?, b"From test1\ntest1\n");
+        assert!(reads.get() > before);
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+// envelope.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::headers::trim_line_ending;
+
+/// The envelope line which starts each message: `From <sender> <date>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FromLine {
+    /// The envelope sender, usually an address.
+    pub sender: Vec<u8>,
+    /// Everything after the sender, usually an asctime()-style date. `None` if the line ends after
+    /// the sender.
+    pub date: Option<Vec<u8>>,
+}
+
+impl FromLine {
+    /// Parses an envelope line, with or without its line ending. Returns `None` if `line` doesn't
+    /// start with `From `.
+    ///
+    /// The sender is everything from the first non-whitespace byte after `From ` up to the next
+    /// whitespace, and the date is the rest of the line. Either may be empty: `From ` alone parses
+    /// as an empty sender with no date.
+    pub fn parse(line: &[u8]) -> Option<FromLine> {
+        let rest = trim_line_ending(line).strip_prefix(b"From ")?;
+        let rest = trim_start(rest);
+        let sender_len = rest
+            .iter()
+            .position(|b| b.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let date = rest[sender_len..].trim_ascii();
+        Some(FromLine {
+            sender: rest[..sender_len].to_vec(),
+            date: (!date.is_empty()).then(|| date.to_vec()),
+        })
+    }
+
+    /// Returns the envelope date as seconds since the Unix epoch, or `None` if there is no date or
+    /// it can't be parsed.
+    ///
+    /// The date is expected in asctime() form (`Thu Jan  1 00:00:00 1970`), which has no time
+    /// zone and is treated as UTC. Common variants are accepted: the weekday may be missing, the
+    /// seconds may be missing, and a numeric or named zone may appear before or after the year
+    /// (in which case a numeric offset is applied).
+    pub fn timestamp(&self) -> Option<i64> {
+        parse_asctime(self.date.as_deref()?)
+    }
+
+    /// Returns the line in a normalized form, `From <sender> <date>` with the date as ISO 8601 in
+    /// UTC (`1970-01-01T00:00:00Z`), without a line ending, so envelopes written by different
+    /// tools can be compared. The date is left out if there is none. One which can't be parsed is
+    /// kept as it is, with runs of whitespace collapsed to single spaces.
+    pub fn canonical(&self) -> Vec<u8> {
+        let mut line = b"From ".to_vec();
+        line.extend_from_slice(&self.sender);
+        if let Some(date) = &self.date {
+            line.push(b' ');
+            match self.timestamp() {
+                Some(timestamp) => line.extend_from_slice(format_iso8601(timestamp).as_bytes()),
+                None => {
+                    let words = date
+                        .split(|b| b.is_ascii_whitespace())
+                        .filter(|w| !w.is_empty());
+                    line.extend_from_slice(&words.collect::<Vec<_>>().join(&b' '));
+                }
+            }
+        }
+        line
+    }
+}
+
+fn trim_start(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+const MONTHS: [&[u8; 3]; 12] = [
+    b"jan", b"feb", b"mar", b"apr", b"may", b"jun", b"jul", b"aug", b"sep", b"oct", b"nov", b"dec",
+];
+
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const WEEKDAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Formats seconds since the Unix epoch the way envelope lines have dates, in asctime() form
+/// (`Thu Jan  1 00:00:00 1970`) in UTC.
+pub(crate) fn format_asctime(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}",
+        // 1970-01-01 was a Thursday
+        WEEKDAY_NAMES[(days + 4).rem_euclid(7) as usize],
+        MONTH_NAMES[month as usize - 1],
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        year
+    )
+}
+
+/// Formats seconds since the Unix epoch as an ISO 8601 date and time in UTC
+/// (`1970-01-01T00:00:00Z`).
+fn format_iso8601(timestamp: i64) -> String {
+    let (year, month, day) = civil_from_days(timestamp.div_euclid(86400));
+    let seconds = timestamp.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn parse_asctime(date: &[u8]) -> Option<i64> {
+    let mut month: Option<u32> = None;
+    let mut day: Option<u32> = None;
+    let mut time: Option<(u32, u32, u32)> = None;
+    let mut year: Option<i64> = None;
+    let mut offset: i64 = 0;
+
+    for token in date
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|t| !t.is_empty())
+    {
+        if month.is_none() && token.len() >= 3 {
+            if let Some(i) = MONTHS
+                .iter()
+                .position(|m| token[..3].eq_ignore_ascii_case(&m[..]))
+            {
+                month = Some(i as u32 + 1);
+                continue;
+            }
+        }
+        if token.contains(&b':') {
+            let mut parts = token.split(|&b| b == b':').map(parse_number);
+            let hour = parts.next()??;
+            let minute = parts.next()??;
+            let second = parts.next().unwrap_or(Some(0))?;
+            time = Some((hour as u32, minute as u32, second as u32));
+        } else if (token[0] == b'+' || token[0] == b'-') && token.len() == 5 {
+            let hhmm = parse_number(&token[1..])?;
+            offset = (hhmm / 100 * 3600 + hhmm % 100 * 60) * if token[0] == b'-' { -1 } else { 1 };
+        } else if let Some(n) = parse_number(token) {
+            if month.is_some() && day.is_none() && token.len() <= 2 {
+                day = Some(n as u32);
+            } else if token.len() == 4 {
+                year = Some(n);
+            }
+        }
+        // anything else is a weekday or zone name
+    }
+
+    let (hour, minute, second) = time?;
+    let (month, day) = (month?, day?);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = days_from_civil(year?, month, day);
+    Some(days * 86400 + (hour * 3600 + minute * 60 + second) as i64 - offset)
+}
+
+fn parse_number(digits: &[u8]) -> Option<i64> {
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) || digits.len() > 9 {
+        return None;
+    }
+    Some(digits.iter().fold(0, |n, &d| n * 10 + i64::from(d - b'0')))
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar (Howard Hinnant's algorithm).
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The year, month and day of a number of days since 1970-01-01, the inverse of
+/// [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let line = FromLine::parse(b"From alice@example.com Thu Jan  1 00:00:00 1970\r\n").unwrap();
+        assert_eq!(line.sender, b"alice@example.com");
+        assert_eq!(line.date.as_deref(), Some(&b"Thu Jan  1 00:00:00 1970"[..]));
+        assert_eq!(line.timestamp(), Some(0));
+        assert_eq!(FromLine::parse(b"Subject: hi\n"), None);
+    }
+
+    #[test]
+    fn parse_without_date() {
+        let line = FromLine::parse(b"From foo@bar.com\n").unwrap();
+        assert_eq!(line.sender, b"foo@bar.com");
+        assert_eq!(line.date, None);
+        assert_eq!(line.timestamp(), None);
+
+        let line = FromLine::parse(b"From foo@bar.com   \r\n").unwrap();
+        assert_eq!(line.sender, b"foo@bar.com");
+        assert_eq!(line.date, None);
+
+        let line = FromLine::parse(b"From ").unwrap();
+        assert_eq!(line.sender, b"");
+        assert_eq!(line.date, None);
+
+        let line = FromLine::parse(b"From \tfoo@bar.com\tMon Jan  1 00:00:00 2001").unwrap();
+        assert_eq!(line.sender, b"foo@bar.com");
+        assert_eq!(line.date.as_deref(), Some(&b"Mon Jan  1 00:00:00 2001"[..]));
+    }
+
+    #[test]
+    fn timestamps() {
+        let timestamp = |date: &str| {
+            FromLine::parse(format!("From a {}", date).as_bytes())
+                .unwrap()
+                .timestamp()
+        };
+        assert_eq!(timestamp("Sat Feb 29 12:34:56 2020"), Some(1582979696));
+        assert_eq!(timestamp("Feb 29 12:34 2020"), Some(1582979640));
+        assert_eq!(
+            timestamp("Sat Feb 29 12:34:56 +0100 2020"),
+            Some(1582976096)
+        );
+        assert_eq!(timestamp("Sat Feb 29 12:34:56 2020 UTC"), Some(1582979696));
+        assert_eq!(timestamp("Mon Dec 31 23:59:59 1969"), Some(-1));
+        assert_eq!(timestamp("yesterday"), None);
+        assert_eq!(timestamp("Sat Feb 29 2020"), None);
+    }
+
+    #[test]
+    fn canonical() {
+        let canonical = |line: &[u8]| FromLine::parse(line).unwrap().canonical();
+        for line in [
+            &b"From alice@example.com Sat Feb 29 12:34:56 2020\n"[..],
+            b"From  alice@example.com  Feb 29 12:34:56 2020\r\n",
+            b"From alice@example.com\tSat Feb 29 13:34:56 +0100 2020",
+            b"From alice@example.com Sat Feb 29 12:34:56 2020 UTC",
+        ] {
+            assert_eq!(
+                canonical(line),
+                b"From alice@example.com 2020-02-29T12:34:56Z"
+            );
+        }
+        assert_eq!(
+            canonical(b"From alice@example.com \n"),
+            b"From alice@example.com"
+        );
+        assert_eq!(canonical(b"From a  some \t day\n"), b"From a some day");
+        assert_eq!(
+            canonical(b"From a Wed Dec 31 23:59:59 1969"),
+            b"From a 1969-12-31T23:59:59Z"
+        );
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format_asctime(0), "Thu Jan  1 00:00:00 1970");
+        assert_eq!(format_asctime(1582979696), "Sat Feb 29 12:34:56 2020");
+        assert_eq!(format_asctime(-1), "Wed Dec 31 23:59:59 1969");
+        for timestamp in [951782400, 4107542399, -2208988800, 1234567890] {
+            let line = FromLine::parse(format!("From a {}", format_asctime(timestamp)).as_bytes());
+            assert_eq!(line.unwrap().timestamp(), Some(timestamp));
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// error.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use std::fmt;
+use std::io;
+
+/// A way in which a message is malformed, found by a reader in [`strict`] mode. It is returned
+/// inside an `InvalidData` [`io::Error`], and can be had back with `get_ref` and `downcast_ref`.
+/// Messages are numbered from 0, or from the resume point if there was one, like
+/// [`ResumeToken::message_number`].
+///
+/// [`strict`]: crate::MboxReader::strict
+/// [`ResumeToken::message_number`]: crate::ResumeToken::message_number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MboxError {
+    /// The message doesn't start with an envelope line which has both a sender and a date that
+    /// [`FromLine::timestamp`](crate::FromLine::timestamp) can parse.
+    BadEnvelope { message: usize },
+    /// The message isn't followed by a blank line before the next one starts.
+    MissingBlankLine { message: usize },
+    /// The message's body isn't as long as its `Content-Length` header says, allowing for the
+    /// blank line after it. `content_length` is `None` if the header isn't a number.
+    ContentLengthMismatch {
+        message: usize,
+        content_length: Option<u64>,
+        body_len: u64,
+    },
+}
+
+impl MboxError {
+    /// The number of the malformed message.
+    pub fn message(&self) -> usize {
+        match *self {
+            MboxError::BadEnvelope { message }
+            | MboxError::MissingBlankLine { message }
+            | MboxError::ContentLengthMismatch { message, .. } => message,
+        }
+    }
+}
+
+impl fmt::Display for MboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MboxError::BadEnvelope { message } => {
+                write!(f, "message {} has a malformed envelope line", message)
+            }
+            MboxError::MissingBlankLine { message } => {
+                write!(f, "message {} isn't followed by a blank line", message)
+            }
+            MboxError::ContentLengthMismatch {
+                message,
+                content_length: Some(content_length),
+                body_len,
+            } => write!(
+                f,
+                "message {} has a {} byte body but a Content-Length of {}",
+                message, body_len, content_length
+            ),
+            MboxError::ContentLengthMismatch { message, .. } => {
+                write!(f, "message {} has an invalid Content-Length", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MboxError {}
+
+impl From<MboxError> for io::Error {
+    fn from(error: MboxError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
@@ -0,0 +1,235 @@
+// headers.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use memchr::memchr;
+
+/// Splits a raw header block into `(name, value)` pairs, joining folded continuation lines (those
+/// beginning with a space or tab) onto the value they continue. Parsing stops at the first empty
+/// line, so the block may include the blank line that separates headers from the body.
+///
+/// Per RFC 5322 unfolding, only the line break is removed: a continuation keeps its leading
+/// whitespace. Leading whitespace after the colon and the trailing line ending are trimmed. Lines
+/// which are not headers (no colon, or a name containing whitespace, like an envelope `From ` line)
+/// are skipped, along with any continuations of them.
+pub fn unfold_headers(raw: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut headers: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    // whether the last line we saw was a header, so continuation lines have something to continue
+    let mut in_header = false;
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        let line = trim_line_ending(line);
+        if line.is_empty() {
+            break;
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            if in_header {
+                headers.last_mut().unwrap().1.extend_from_slice(line);
+            }
+            continue;
+        }
+        in_header = false;
+        if let Some(colon) = memchr(b':', line) {
+            let name = &line[..colon];
+            if !name.is_empty() && !name.iter().any(|b| b.is_ascii_whitespace()) {
+                let value = &line[colon + 1..];
+                let start = value
+                    .iter()
+                    .position(|&b| b != b' ' && b != b'\t')
+                    .unwrap_or(value.len());
+                headers.push((name.to_vec(), value[start..].to_vec()));
+                in_header = true;
+            }
+        }
+    }
+    headers
+}
+
+/// Formats a header line, folding it so lines are at most `width` bytes where possible. Folds are
+/// only inserted before existing whitespace, so a token longer than `width` is left intact and
+/// [`unfold_headers`] returns exactly the original `value`. The result ends with `\n`.
+pub fn fold_header(name: &[u8], value: &[u8], width: usize) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(name.len() + value.len() + 4);
+    out.extend_from_slice(name);
+    out.extend_from_slice(b": ");
+    let mut line_len = out.len();
+
+    let mut rest = value;
+    let mut first = true;
+    while !rest.is_empty() {
+        // each segment is a run of whitespace followed by a run of anything else
+        let word_start = rest
+            .iter()
+            .position(|&b| b != b' ' && b != b'\t')
+            .unwrap_or(rest.len());
+        let word_end = rest[word_start..]
+            .iter()
+            .position(|&b| b == b' ' || b == b'\t')
+            .map_or(rest.len(), |i| word_start + i);
+        let segment = &rest[..word_end];
+        if !first && word_start > 0 && line_len + segment.len() > width {
+            out.push(b'\n');
+            line_len = 0;
+        }
+        out.extend_from_slice(segment);
+        line_len += segment.len();
+        rest = &rest[word_end..];
+        first = false;
+    }
+    out.push(b'\n');
+    out
+}
+
+/// The headers used to group messages into conversations.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ThreadingInfo {
+    pub message_id: Option<Vec<u8>>,
+    pub in_reply_to: Option<Vec<u8>>,
+    /// The individual message IDs listed in `References`, oldest first.
+    pub references: Vec<Vec<u8>>,
+}
+
+impl ThreadingInfo {
+    pub(crate) fn from_headers(headers: &[(Vec<u8>, Vec<u8>)]) -> Self {
+        let mut info = ThreadingInfo::default();
+        for (name, value) in headers {
+            let value = value.trim_ascii();
+            if name.eq_ignore_ascii_case(b"Message-ID") {
+                info.message_id = Some(value.to_vec());
+            } else if name.eq_ignore_ascii_case(b"In-Reply-To") {
+                info.in_reply_to = Some(value.to_vec());
+            } else if name.eq_ignore_ascii_case(b"References") {
+                info.references = value
+                    .split(|b| b.is_ascii_whitespace() || *b == b',')
+                    .filter(|id| !id.is_empty())
+                    .map(|id| id.to_vec())
+                    .collect();
+            }
+        }
+        info
+    }
+}
+
+/// Message state as recorded by mail clients in the `Status` and `X-Status` headers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MessageFlags {
+    pub seen: bool,
+    pub answered: bool,
+    pub flagged: bool,
+    pub deleted: bool,
+    pub draft: bool,
+}
+
+impl MessageFlags {
+    /// Maps the letter codes `R` (seen), `A` (answered), `F` (flagged), `D` (deleted) and `T`
+    /// (draft). Clients disagree about which header holds which letter, so both are checked. `O`
+    /// (old, i.e. not new) has no flag of its own and is ignored, as are unknown letters.
+    pub(crate) fn from_headers(headers: &[(Vec<u8>, Vec<u8>)]) -> Self {
+        let mut flags = MessageFlags::default();
+        for (name, value) in headers {
+            if !name.eq_ignore_ascii_case(b"Status") && !name.eq_ignore_ascii_case(b"X-Status") {
+                continue;
+            }
+            for letter in value {
+                match letter {
+                    b'R' => flags.seen = true,
+                    b'A' => flags.answered = true,
+                    b'F' => flags.flagged = true,
+                    b'D' => flags.deleted = true,
+                    b'T' => flags.draft = true,
+                    _ => {}
+                }
+            }
+        }
+        flags
+    }
+}
+
+pub(crate) fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unfolding() {
+        let raw = b"Subject: a very long subject\r\n  which keeps going\r\n\tand going\r\nTo:  bob@example.com\r\n\r\nBody: not a header\r\n";
+        let headers = unfold_headers(raw);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].0, b"Subject");
+        assert_eq!(
+            headers[0].1,
+            b"a very long subject  which keeps going\tand going"
+        );
+        assert_eq!(headers[1].0, b"To");
+        assert_eq!(headers[1].1, b"bob@example.com");
+    }
+
+    #[test]
+    fn unfolding_skips_non_headers() {
+        let raw = b"From alice@example.com Thu Jan  1 00:00:00 1970\n continued\nX-Test: yes\n";
+        let headers = unfold_headers(raw);
+        assert_eq!(headers, [(b"X-Test".to_vec(), b"yes".to_vec())]);
+    }
+
+    #[test]
+    fn folding() {
+        let value = b"<one@example.com> <two@example.com> <three@example.com>";
+        let folded = fold_header(b"References", value, 40);
+        assert_eq!(
+            folded,
+            b"References: <one@example.com>\n <two@example.com> <three@example.com>\n"
+        );
+        assert!(folded.split(|&b| b == b'\n').all(|line| line.len() <= 40));
+        let headers = unfold_headers(&folded);
+        assert_eq!(headers, [(b"References".to_vec(), value.to_vec())]);
+    }
+
+    #[test]
+    fn folding_long_token() {
+        let value = b"short\tthisisaverylongtokenwithnowhitespace end";
+        let folded = fold_header(b"X-Long", value, 10);
+        assert_eq!(
+            folded,
+            b"X-Long: short\n\tthisisaverylongtokenwithnowhitespace\n end\n"
+        );
+        assert_eq!(unfold_headers(&folded)[0].1, value);
+    }
+
+    #[test]
+    fn message_flags() {
+        let flags = |raw: &[u8]| MessageFlags::from_headers(&unfold_headers(raw));
+        assert_eq!(flags(b"Subject: hi\n\n"), MessageFlags::default());
+        assert_eq!(
+            flags(b"Status: O\n\n"),
+            MessageFlags::default(),
+            "old but unread"
+        );
+        assert_eq!(
+            flags(b"Status: RO\nX-Status: A\n\n"),
+            MessageFlags {
+                seen: true,
+                answered: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            flags(b"Status: RO\nX-Status: DF\n\n"),
+            MessageFlags {
+                seen: true,
+                flagged: true,
+                deleted: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            flags(b"X-Status: T\n\n"),
+            MessageFlags {
+                draft: true,
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,225 @@
+// index.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::{unfold_headers, MboxReader};
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Where one message is in a file, as recorded by [`MboxReader::build_index_detailed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The offset of the message's `From ` line.
+    pub offset: u64,
+    /// The length of the envelope and headers, including the blank line which ends them.
+    pub header_len: u64,
+    /// The length of the whole message, up to the start of the next one.
+    pub len: u64,
+}
+
+impl IndexEntry {
+    pub fn range(&self) -> MessageRange {
+        MessageRange {
+            start: self.offset,
+            end: self.offset + self.len,
+        }
+    }
+}
+
+/// The bytes of a file which hold one message, from the start of its `From ` line up to the start
+/// of the next message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl MessageRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// The location of every message in a file, in file order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MboxIndex {
+    entries: Vec<IndexEntry>,
+    fingerprint: Option<u64>,
+}
+
+impl MboxIndex {
+    pub(crate) fn new(entries: Vec<IndexEntry>) -> Self {
+        MboxIndex {
+            entries,
+            fingerprint: None,
+        }
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, n: usize) -> Option<&IndexEntry> {
+        self.entries.get(n)
+    }
+
+    /// The [`MboxReader::file_fingerprint`] of the file the index was built from, if it was
+    /// recorded.
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+
+    pub fn set_fingerprint(&mut self, fingerprint: u64) {
+        self.fingerprint = Some(fingerprint);
+    }
+
+    /// Returns whether `reader`'s file still has the fingerprint recorded in the index, i.e.
+    /// whether the index can still be used for it. Returns false if no fingerprint was recorded.
+    pub fn is_current<R: Read + Seek>(&self, reader: &mut MboxReader<R>) -> io::Result<bool> {
+        match self.fingerprint {
+            Some(fingerprint) => Ok(reader.file_fingerprint()? == fingerprint),
+            None => Ok(false),
+        }
+    }
+
+    /// Checks each message which has a `Content-Length` header against the index, as written by
+    /// mboxcl-style mailers, and returns the indices of those which don't match. A message matches
+    /// if its body is exactly `Content-Length` bytes, optionally followed by the blank line (`\n`
+    /// or `\r\n`) which separates it from the next message. A `Content-Length` which isn't a number
+    /// doesn't match. Messages without the header aren't checked.
+    ///
+    /// `reader` must read the file the index was built from. It is left at an unspecified
+    /// position, so seek it (e.g. with `read_message_at`) before reading from it again.
+    pub fn check_content_lengths<R: Read + Seek>(
+        &self,
+        reader: &mut MboxReader<R>,
+    ) -> io::Result<Vec<usize>> {
+        let mut mismatches = Vec::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            reader.seek_to_message(entry.offset)?;
+            let content_length = match reader.next()? {
+                Some(mut message) => unfold_headers(message.head()?)
+                    .into_iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(b"Content-Length"))
+                    .map(|(_, value)| parse_length(&value)),
+                None => None,
+            };
+            let matches = match content_length {
+                None => continue,
+                Some(None) => false,
+                Some(Some(length)) => ends_after(reader, entry, length)?,
+            };
+            if !matches {
+                mismatches.push(i);
+            }
+        }
+        Ok(mismatches)
+    }
+}
+
+pub(crate) fn parse_length(value: &[u8]) -> Option<u64> {
+    let digits = value.trim_ascii();
+    if digits.is_empty() || digits.len() > 19 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(digits.iter().fold(0, |n, &d| n * 10 + u64::from(d - b'0')))
+}
+
+/// Returns whether `entry`'s body is `length` bytes followed by nothing but an optional blank line.
+/// Reads the underlying stream directly, since escaping `>` characters count towards the length.
+fn ends_after<R: Read + Seek>(
+    reader: &mut MboxReader<R>,
+    entry: &IndexEntry,
+    length: u64,
+) -> io::Result<bool> {
+    let body_len = entry.len - entry.header_len;
+    let extra = match body_len.checked_sub(length) {
+        Some(extra) if extra <= 2 => extra as usize,
+        _ => return Ok(false),
+    };
+    let mut tail = [0; 2];
+    let inner = &mut reader.inner.inner;
+    inner.seek(SeekFrom::Start(entry.offset + entry.header_len + length))?;
+    inner.read_exact(&mut tail[..extra])?;
+    Ok(matches!(&tail[..extra], b"" | b"\n" | b"\r\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn build_index_detailed() -> io::Result<()> {
+        let input =
+            b"From a\nSubject: 1\n\nbody\n\nFrom b\r\nSubject: 2\r\n\r\n>From body\r\nFrom c\n";
+        let index = MboxReader::new(input.as_slice()).build_index_detailed()?;
+        assert_eq!(
+            index.entries(),
+            [
+                IndexEntry {
+                    offset: 0,
+                    header_len: 19,
+                    len: 25,
+                },
+                IndexEntry {
+                    offset: 25,
+                    header_len: 22,
+                    len: 34,
+                },
+                IndexEntry {
+                    offset: 59,
+                    header_len: 7,
+                    len: 7,
+                },
+            ]
+        );
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.get(3), None);
+        Ok(())
+    }
+
+    #[test]
+    fn check_content_lengths() -> io::Result<()> {
+        let input = concat!(
+            "From a\nContent-Length: 5\n\nbody\n\n",
+            "From b\nContent-Length: 3\n\nbody\n\n",
+            "From c\n\nno length\n\n",
+            "From d\nContent-Length: 12\n\n>From a body\n",
+            "From e\nContent-Length: many\n\nbody\n\n",
+            "From f\r\nContent-Length: 6\r\n\r\nbody\r\n\r\n",
+            "From g\nContent-Length: 4\n\nbodyxx\n",
+        );
+        let mut reader = MboxReader::new(Cursor::new(input.as_bytes()));
+        let index = reader.build_index_detailed()?;
+        assert_eq!(index.len(), 7);
+        assert_eq!(index.check_content_lengths(&mut reader)?, [1, 4, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn is_current() -> io::Result<()> {
+        let input = b"From a\n\nfirst\n\nFrom b\n\nsecond\n".to_vec();
+        let mut reader = MboxReader::new(Cursor::new(input.clone()));
+        let index = reader.build_index_with_fingerprint()?;
+        assert_eq!(index.len(), 2);
+        assert!(index.is_current(&mut reader)?);
+        assert!(!reader.build_index_detailed()?.is_current(&mut reader)?);
+
+        let mut appended = input;
+        appended.extend_from_slice(b"\nFrom c\n");
+        assert!(!index.is_current(&mut MboxReader::new(Cursor::new(appended)))?);
+        Ok(())
+    }
+}
@@ -2,400 +2,5391 @@
 // mbox-indexer
 // Copyright 2022 Andrew Morrow. All rights reserved.
 
-use memchr::{memchr, memmem};
-use std::io::{self, BufRead, Read};
+use memchr::{memchr, memmem, memrchr};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod base64;
+mod buffered;
+mod cache;
+mod envelope;
+mod error;
+mod headers;
+mod index;
+mod merge;
+mod mime;
+mod quoted_printable;
+mod scanner;
+mod writer;
+
+#[cfg(feature = "base64")]
+pub use base64::Base64Reader;
+pub use buffered::{BufferedMessages, Recycler};
+pub use cache::CachedMboxReader;
+pub use envelope::FromLine;
+pub use error::MboxError;
+pub use headers::{fold_header, unfold_headers, MessageFlags, ThreadingInfo};
+pub use index::{IndexEntry, MboxIndex, MessageRange};
+pub use merge::merge_by_date;
+#[cfg(feature = "quoted-printable")]
+pub use quoted_printable::QuotedPrintableReader;
+pub use scanner::Scanner;
+pub use writer::{remove_message, repair, rewrite, Dialect, MboxWriter};
+
+/// Splits an mbox stream into messages.
+///
+/// Errors from the underlying reader, including `WouldBlock` from a non-blocking one and
+/// `Interrupted`, are returned as they are, and nothing read before them is lost: the call can be
+/// retried once the reader is ready again. Reading an [`MboxEntry`] only returns `Ok(0)` at the end
+/// of the message or of the stream, never because no data is available yet, and
+/// [`MboxEntry::at_boundary`] tells those apart.
+///
+/// The newline which ends a message is only returned once the `From ` after it has been read, so
+/// by the time a message's last byte has been read, where the next one starts is already known
+/// and `next` can return it without reading anything more. The cost is that up to a buffer's
+/// worth of the next message has been read too.
 pub struct MboxReader<R> {
     inner: MessageBoundaryReader<R>,
-    first: bool,
+    /// True when the reader is positioned at the start of a message which hasn't been returned by
+    /// `next` yet: either the first message, or one we stopped on after inspecting its head.
+    pending: bool,
+    head: Head,
+    /// True until the first call to `next`.
+    at_start: bool,
+    strip_bom: bool,
+    trim_leading: bool,
+    skip_imap_header: bool,
+    skip_empty: bool,
+    synthesize_missing_envelopes: bool,
+    strict: bool,
+    /// What strict mode has left to check about the current message once its end is reached.
+    end_check: Option<EndCheck>,
+    /// How many messages `next` has returned, counting from the resume point if there was one.
+    messages_returned: usize,
+    /// The offset of the message most recently returned by `next`.
+    last_start: Option<u64>,
+    /// Set when unread bodies are skipped by their `Content-Length`, to seek the underlying
+    /// reader, which this part of the code doesn't know is `Seek`.
+    content_length_seek: Option<fn(&mut R, SeekFrom) -> io::Result<u64>>,
 }
 
 pub struct MboxEntry<'a, R> {
     inner: &'a mut MessageBoundaryReader<R>,
+    head: &'a mut Head,
 }
 
-impl<'a, R: Read> MboxReader<R> {
-    pub fn new(inner: R) -> Self {
-        MboxReader {
-            inner: MessageBoundaryReader::new(inner),
-            first: true,
-        }
-    }
+/// A message split into its parts by [`MboxReader::parse_all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedMessage {
+    /// The envelope line, which only the first message can be missing.
+    pub from: Option<FromLine>,
+    /// The headers in order, unfolded (see [`unfold_headers`]).
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Everything after the blank line which ends the headers.
+    pub body: Vec<u8>,
+}
 
-    // Cannot implement std::iter::Iterator because of self-referential struct
-    pub fn next(&'a mut self) -> io::Result<Option<MboxEntry<'a, R>>> {
-        if self.inner.eof()? {
-            return Ok(None);
-        }
-        if self.first {
-            self.first = false;
-            return Ok(Some(MboxEntry {
-                inner: &mut self.inner,
-            }));
-        }
-        if !self.inner.eom() {
-            self.inner.skip_message()?;
-            if self.inner.eof()? {
-                return Ok(None);
-            }
+/// What came before a message's `From ` line, from [`MboxEntry::leading_separator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeadingSeparator {
+    /// Nothing: the message is the first in the stream.
+    None,
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+impl LeadingSeparator {
+    fn before(byte_before_last: Option<u8>, last_byte: Option<u8>) -> Self {
+        match (byte_before_last, last_byte) {
+            (Some(b'\r'), Some(b'\n')) => LeadingSeparator::CrLf,
+            (_, Some(b'\n')) => LeadingSeparator::Lf,
+            _ => LeadingSeparator::None,
         }
-        assert!(self.inner.eom());
-        self.inner.reset_eom();
-        Ok(Some(MboxEntry {
-            inner: &mut self.inner,
-        }))
     }
 }
 
-impl<'a, R: Read> Read for MboxEntry<'a, R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
-    }
+/// Where [`MboxReader::count_by_sender`] takes each message's sender from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SenderSource {
+    /// The sender on the envelope line, as in [`FromLine::sender`].
+    Envelope,
+    /// The address in the `From:` header: the part in angle brackets if there is one, and
+    /// otherwise the whole value. Only the first address is used if there are several.
+    FromHeader,
 }
 
-impl<'a, R: Read> BufRead for MboxEntry<'a, R> {
-    fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        self.inner.fill_buf()
+/// Something a reader did with the underlying stream, reported to the hook set with
+/// [`MboxReader::on_event`] for working out why a file splits the way it does. Offsets are those
+/// of the underlying stream, as for `read_message_at`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadEvent {
+    /// The buffer was refilled with `len` bytes, read from `offset` on.
+    Refill { offset: u64, len: usize },
+    /// The last `len` bytes in the buffer, from `offset` on, were held back to be looked at again
+    /// after the next refill, since they may be the start of a boundary or of an escaped line.
+    HeldBack { offset: u64, len: usize },
+    /// A boundary was found: the next message starts at `offset`.
+    Boundary { offset: u64 },
+    /// A read found that the underlying stream ends, `offset` bytes in. This is reported again
+    /// each time the reader reaches the end, e.g. to look again at bytes held back before it.
+    Eof { offset: u64 },
+}
+
+/// Where a reader had got to in a file, from [`MboxReader::resume_token`], so a later process can
+/// pick up from there with [`MboxReader::resume`]. To be saved in between, it converts to and from
+/// a line of text: the three fields in order, separated by spaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeToken {
+    /// The offset of the next message to be read.
+    pub offset: u64,
+    /// How many messages had been read before it.
+    pub message_number: usize,
+    /// The length of the file, checked on resuming to detect that it has changed.
+    pub file_len: u64,
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.offset, self.message_number, self.file_len)
     }
+}
 
-    fn consume(&mut self, amt: usize) {
-        self.inner.consume(amt)
+impl FromStr for ResumeToken {
+    type Err = io::Error;
+
+    /// Parses a token written by `to_string`. Surrounding whitespace, such as the newline ending a
+    /// checkpoint file, is ignored. Anything else gives an `InvalidData` error.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid resume token");
+        let mut fields = s.split_whitespace();
+        let mut field = || fields.next().ok_or_else(invalid);
+        let offset = field()?.parse().map_err(|_| invalid())?;
+        let message_number = field()?.parse().map_err(|_| invalid())?;
+        let file_len = field()?.parse().map_err(|_| invalid())?;
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(ResumeToken {
+            offset,
+            message_number,
+            file_len,
+        })
     }
 }
 
-const DEFAULT_CAPACITY: usize = 8192;
-const MAGIC_WORD: [u8; 6] = [0x0A, 0x46, 0x72, 0x6F, 0x6D, 0x20];
+/// The checks on a message's end which strict mode makes when moving past it.
+struct EndCheck {
+    message: usize,
+    /// Where the body starts and the value of the `Content-Length` header, if it has one (`None`
+    /// inside if it isn't a number).
+    content_length: Option<(u64, Option<u64>)>,
+}
 
-/// MessageBoundaryReader reads bytes until it reaches the "magic word": `From` preceded by a
-/// newline (0x0A) and followed by a space (0x20). When it reaches the "magic word", it will stop
-/// reading (i.e. return 0 bytes) and the `eom` function will return true. To read the next message,
-/// call `reset_eom`.
-struct MessageBoundaryReader<R> {
-    inner: R,
-    buffer: Vec<u8>,
-    buffer_end: usize,
-    ready_start: usize,
-    ready_end: usize,
-    held_back: usize,
-    next_message_start: Option<usize>,
+/// The envelope and header lines of the current message, once they have been read for
+/// inspection. `MboxEntry` hands these bytes back out before reading any further, so inspecting the
+/// head doesn't change what the caller reads.
+#[derive(Default)]
+struct Head {
+    bytes: Vec<u8>,
+    pos: usize,
+    complete: bool,
+    /// How many bytes at the start of `bytes` weren't read from the stream, but made up.
+    synthesized: usize,
+    /// Bytes after the head which have been read to look ahead, and are returned after it.
+    ahead: Vec<u8>,
+    /// Where `MboxEntry::peek` puts bytes from both `bytes` and `ahead`, to return them together.
+    peeked: Vec<u8>,
+    /// Where escaping `>` characters were removed from what was read, so offsets in the stream
+    /// can be worked out. Each is the index of the byte which followed the removed one, counting
+    /// only bytes read from the stream: those in `bytes` after any made up, then those in `ahead`.
+    escapes: Vec<usize>,
+    /// How many bytes of the stream after everything read were skipped at the boundary, when the
+    /// separator line isn't kept or trailing blank lines are trimmed. They come after any unread
+    /// bytes, so count towards them until those have all been returned.
+    dropped: usize,
 }
 
-impl<R: Read> MessageBoundaryReader<R> {
-    fn new(inner: R) -> Self {
-        MessageBoundaryReader {
-            inner,
-            buffer: vec![0; DEFAULT_CAPACITY],
-            buffer_end: 0,
-            ready_start: 0,
-            ready_end: 0,
-            held_back: 0,
-            next_message_start: None,
-        }
+impl Head {
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.pos = 0;
+        self.complete = false;
+        self.synthesized = 0;
+        self.ahead.clear();
+        self.escapes.clear();
+        self.dropped = 0;
     }
 
-    /// Returns true if the reader has stopped before the beginning of the next message. Returns
-    /// false in all other cases, including end of file.
-    ///
-    /// Note: You may have to read until 0 bytes are returned before this function returns true.
-    fn eom(&self) -> bool {
-        self.next_message_start
-            .map_or(false, |i| i == self.ready_start)
+    /// How many bytes have been read from the stream but not yet returned.
+    fn unread(&self) -> usize {
+        self.bytes.len() - self.pos.max(self.synthesized) + self.ahead.len()
     }
 
-    /// Returns true if there is no more data to be read. Returns `io::Result` because it may have
-    /// to read from the underlying stream.
-    fn eof(&mut self) -> io::Result<bool> {
-        Ok(!self.eom() && self.fill_buf()?.is_empty())
+    /// How many bytes of the head were read from the stream.
+    fn read_len(&self) -> usize {
+        self.bytes.len() - self.synthesized
     }
 
-    /// If called when `self.eom() == false`, this triggers undefined behavior
-    fn reset_eom(&mut self) {
-        assert!(self.eom());
-        self.next_message_start = None;
+    /// Like `unread`, but the length in the stream, including escapes which have been removed and
+    /// any bytes dropped after them.
+    fn stream_unread(&self) -> usize {
+        let unread = self.unread();
+        if unread == 0 {
+            return 0;
+        }
+        let returned = self.pos.max(self.synthesized) - self.synthesized;
+        unread + self.escapes.iter().filter(|&&i| i >= returned).count() + self.dropped
     }
 
-    /// Skips all remaining bytes in the current message, possibly reaching EOF. After calling this,
-    /// either `self.eof()` or `self.eom()` will be true.
-    fn skip_message(&mut self) -> io::Result<()> {
-        loop {
-            let ready = self.fill_buf()?.len();
-            if ready == 0 {
-                break;
+    /// Like `read_len`, but the length in the stream, including escapes which have been removed
+    /// and, if nothing has been read after the head, any bytes dropped after it.
+    fn stream_read_len(&self) -> usize {
+        let read_len = self.read_len();
+        let dropped = if self.ahead.is_empty() { self.dropped } else { 0 };
+        read_len + self.escapes.iter().filter(|&&i| i < read_len).count() + dropped
+    }
+
+    /// Notes the escapes removed from bytes just added to the end of `ahead` (or of `bytes`, if
+    /// `ahead` is empty), given as indices into those bytes.
+    fn add_escapes(&mut self, added: usize, escapes: impl Iterator<Item = usize>) {
+        let start = self.read_len() + self.ahead.len() - added;
+        self.escapes.extend(escapes.map(|i| start + i));
+    }
+
+    /// Removes `amt` bytes from the front of `ahead`, as they are returned.
+    fn consume_ahead(&mut self, amt: usize) {
+        self.ahead.drain(..amt);
+        let start = self.read_len();
+        self.escapes.retain(|&i| i < start || i >= start + amt);
+        for i in &mut self.escapes {
+            if *i >= start {
+                *i -= amt;
             }
-            self.consume(ready);
         }
-        Ok(())
     }
-}
 
-impl<R: Read> Read for MessageBoundaryReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let available = self.fill_buf()?;
-        let copied = available.len().min(buf.len());
-        (&mut buf[..copied]).copy_from_slice(&available[..copied]);
-        self.consume(copied);
-        Ok(copied)
+    /// Treats everything read as returned.
+    fn discard(&mut self) {
+        self.pos = self.bytes.len();
+        self.ahead.clear();
+        let read_len = self.read_len();
+        self.escapes.retain(|&i| i < read_len);
     }
 }
 
-impl<R: Read> BufRead for MessageBoundaryReader<R> {
-    fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        if self.ready_start != self.ready_end {
-            // don't refill until all bytes have been consumed
-            return Ok(&self.buffer[self.ready_start..self.ready_end]);
+impl<R: Read> MboxReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY, inner)
+    }
+
+    /// Creates a reader whose buffer starts out at `capacity` bytes, which must be at least 6.
+    ///
+    /// The buffer grows while reading messages too big to fit in it, up to 1 MiB by default (see
+    /// `max_buffer_capacity`), so large messages take fewer refills.
+    pub fn with_capacity(capacity: usize, inner: R) -> Self {
+        MboxReader {
+            inner: MessageBoundaryReader::with_capacity(capacity, inner),
+            pending: true,
+            head: Head::default(),
+            at_start: true,
+            strip_bom: true,
+            trim_leading: false,
+            skip_imap_header: false,
+            skip_empty: false,
+            synthesize_missing_envelopes: false,
+            strict: false,
+            end_check: None,
+            messages_returned: 0,
+            last_start: None,
+            content_length_seek: None,
         }
+    }
 
-        if self.next_message_start.is_some() {
-            assert!(self.eom());
-            return Ok(&[]);
+    // Cannot implement std::iter::Iterator because of self-referential struct
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> io::Result<Option<MboxEntry<'_, R>>> {
+        loop {
+            if !self.advance()? {
+                return Ok(None);
+            }
+            if !self.skip_empty || !self.current_is_empty()? {
+                break;
+            }
+            self.messages_returned -= 1;
         }
+        if self.strict {
+            self.check_start()?;
+        }
+        Ok(Some(MboxEntry {
+            inner: &mut self.inner,
+            head: &mut self.head,
+        }))
+    }
 
-        if self.ready_end == self.held_back {
-            // we read everything in the buffer and it's time to restart at the beginning, possibly
-            // copying held back bytes
-            let num_held_back = self.buffer_end - self.held_back;
-            if num_held_back > 0 {
-                self.buffer.copy_within(self.held_back..self.buffer_end, 0);
+    /// Moves to the start of the next message, returning false at the end of the stream.
+    fn advance(&mut self) -> io::Result<bool> {
+        if self.at_start {
+            self.start()?;
+            if self.synthesize_missing_envelopes {
+                self.synthesize_envelope()?;
+            }
+            if self.skip_imap_header && self.first_is_imap_header()? {
+                // skip it like a message which has been returned
+                self.pending = false;
+            }
+        }
+        if self.pending {
+            self.pending = false;
+            // the head may hold the whole message, in which case the inner reader is already at EOF
+            if self.head.bytes.is_empty() && self.head.ahead.is_empty() && self.inner.eof()? {
+                return Ok(false);
+            }
+            self.messages_returned += 1;
+            self.last_start = Some(self.message_start());
+            return Ok(true);
+        }
+        if let Some(blank_line) = self.skip_by_content_length()? {
+            self.head.clear();
+            self.check_end(self.inner.position(), blank_line, false)?;
+            self.messages_returned += 1;
+            self.last_start = Some(self.message_start());
+            return Ok(true);
+        }
+        self.head.clear();
+        if !self.inner.eom() {
+            self.inner.skip_message()?;
+        }
+        if self.inner.eof()? {
+            let blank_line = self.inner.blank_line_before(self.inner.ready_start);
+            self.check_end(self.inner.position(), blank_line, true)?;
+            return Ok(false);
+        }
+        assert!(self.inner.eom());
+        let next_start = self.inner.next_message_start.unwrap();
+        let (end, blank_line) = (
+            self.inner.offset_of(next_start),
+            self.inner.blank_line_before(next_start),
+        );
+        self.inner.reset_eom();
+        self.inner.leading_separator =
+            LeadingSeparator::before(self.inner.byte_before_last, self.inner.last_byte);
+        self.check_end(end, blank_line, false)?;
+        self.messages_returned += 1;
+        self.last_start = Some(self.message_start());
+        Ok(true)
+    }
+
+    /// Skips the rest of the current message by seeking past as many body bytes as its
+    /// `Content-Length` says, if `content_length` is on and the header is there, and returns the
+    /// length of the blank line after it. Returns `None`, having changed nothing but perhaps read
+    /// the head, if that can't be done: the header is missing or wrong, or the message is the
+    /// last, or part of the body has already been read before the head was.
+    fn skip_by_content_length(&mut self) -> io::Result<Option<usize>> {
+        let (seek, start) = match (self.content_length_seek, self.last_start) {
+            (Some(seek), Some(start)) if !self.inner.eom() && !self.inner.single_message => {
+                (seek, start)
+            }
+            _ => return Ok(None),
+        };
+        if !self.head.complete {
+            // everything read so far must be in the head, or the body start isn't known
+            let head = &self.head;
+            let read = head.read_len() + head.ahead.len() + head.escapes.len() + head.dropped;
+            if self.inner.position() - start != read as u64 {
+                return Ok(None);
+            }
+            let mut entry = MboxEntry {
+                inner: &mut self.inner,
+                head: &mut self.head,
+            };
+            entry.head()?;
+        }
+        let length = match unfold_headers(&self.head.bytes)
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(b"Content-Length"))
+            .and_then(|(_, value)| index::parse_length(&value))
+        {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+        // the body ends with a newline, then there may be a blank line, then the next `From `
+        let body_end = start + self.head.stream_read_len() as u64 + length;
+        let inner = &mut self.inner.inner;
+        let resume_at = seek(inner, SeekFrom::Current(0))?;
+        // from two bytes before the end, so the line ending before `From ` can be recorded
+        seek(inner, SeekFrom::Start(body_end - 2))?;
+        let mut around = Vec::with_capacity(9);
+        inner.by_ref().take(9).read_to_end(&mut around)?;
+        let separator = match around.get(1) {
+            Some(b'\n') => [&b""[..], b"\n", b"\r\n"].into_iter().find(|separator| {
+                around[2..]
+                    .strip_prefix(*separator)
+                    .is_some_and(|rest| rest.starts_with(b"From "))
+            }),
+            _ => None,
+        };
+        let separator_len = match separator {
+            Some(separator) => separator.len(),
+            None => {
+                seek(inner, SeekFrom::Start(resume_at))?;
+                return Ok(None);
             }
+        };
+        let next_start = body_end + separator_len as u64;
+        seek(inner, SeekFrom::Start(next_start))?;
+        let preceding = &around[separator_len..separator_len + 2];
+        self.inner.reset_to(next_start, preceding);
+        Ok(Some(separator_len))
+    }
 
-            self.ready_start = 0;
-            self.ready_end = 0; // the bytes aren't ready until we've checked them for the magic word
-            self.buffer_end = num_held_back;
-            self.held_back = num_held_back; // because it's equal to buffer_end, 0 bytes are held back
+    /// Checks the start of the message just moved to, for strict mode, and notes what to check
+    /// about its end.
+    fn check_start(&mut self) -> io::Result<()> {
+        let message = self.messages_returned - 1;
+        let start = self.message_start();
+        let mut entry = MboxEntry {
+            inner: &mut self.inner,
+            head: &mut self.head,
+        };
+        let envelope = FromLine::parse(entry.envelope()?);
+        if !envelope.is_some_and(|from| !from.sender.is_empty() && from.timestamp().is_some()) {
+            return Err(MboxError::BadEnvelope { message }.into());
+        }
+        let content_length = unfold_headers(entry.head()?)
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(b"Content-Length"))
+            .map(|(_, value)| {
+                let body_start = start + self.head.stream_read_len() as u64;
+                (body_start, index::parse_length(&value))
+            });
+        self.end_check = Some(EndCheck {
+            message,
+            content_length,
+        });
+        Ok(())
+    }
 
-            while self.buffer_end < self.buffer.len() {
-                let bytes_read = self.inner.read(&mut self.buffer[self.buffer_end..])?;
-                self.buffer_end += bytes_read;
-                self.held_back = self.buffer_end;
-                if bytes_read == 0 {
-                    break;
+    /// Checks the end of the message which has just been moved past, for strict mode, given the
+    /// offset where it ends and the length of the blank line it ends with, if any. On an error,
+    /// the reader stays at the start of the next message.
+    fn check_end(&mut self, end: u64, blank_line: usize, at_eof: bool) -> io::Result<()> {
+        let check = match self.end_check.take() {
+            Some(check) => check,
+            None => return Ok(()),
+        };
+        let message = check.message;
+        let error = match check.content_length {
+            _ if !at_eof && blank_line == 0 => MboxError::MissingBlankLine { message },
+            Some((body_start, content_length)) => {
+                let body_len = end - body_start;
+                let without_blank_line = body_len.checked_sub(blank_line as u64);
+                if content_length
+                    .is_some_and(|length| body_len == length || without_blank_line == Some(length))
+                {
+                    return Ok(());
+                }
+                MboxError::ContentLengthMismatch {
+                    message,
+                    content_length,
+                    body_len,
                 }
             }
+            None => return Ok(()),
+        };
+        // stop at the next message, so it's returned after the error
+        self.pending = true;
+        Err(error.into())
+    }
 
-            if self.buffer_end >= MAGIC_WORD.len() {
-                // as long as there are six bytes or more in the buffer, we want to check for
-                // newlines in the last five and hold those back
-                // if there are five or fewer bytes, then we're at source EOF and don't need to
-                let last5 = &self.buffer[self.buffer_end - 5..self.buffer_end];
-                if let Some(newline_idx) = memchr(b'\n', last5) {
-                    self.held_back = self.buffer_end - 5 + newline_idx;
-                }
+    /// Whether the message the reader is at has nothing but whitespace after its envelope line.
+    /// Whitespace which has to be read past to find out is kept as lookahead, so it's still
+    /// returned if the message turns out not to be empty.
+    fn current_is_empty(&mut self) -> io::Result<bool> {
+        let mut entry = MboxEntry {
+            inner: &mut self.inner,
+            head: &mut self.head,
+        };
+        let envelope_len = entry.from_line_len()?;
+        if !entry.head()?[envelope_len..].iter().all(u8::is_ascii_whitespace) {
+            return Ok(false);
+        }
+        loop {
+            let before = self.inner.position();
+            let available = self.inner.fill_buf()?;
+            if available.is_empty() {
+                self.head.dropped += (self.inner.position() - before) as usize;
+                return Ok(true);
+            }
+            let blank = available.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            let rest = available.len() - blank;
+            self.head.ahead.extend_from_slice(&available[..blank]);
+            self.head.add_escapes(blank, self.inner.escapes_in_next(blank));
+            self.inner.consume(blank);
+            if rest > 0 {
+                return Ok(false);
             }
-        } else {
-            // we called reset_eom() and are continuing to read pre-buffered content
-            // we don't want to reset any offsets - they are all still accurate
         }
+    }
 
-        // assumptions:
-        // this has to be true, because it's already been checked
-        assert_eq!(self.ready_start, self.ready_end);
+    /// Makes up an envelope line for the first message if it doesn't start with one, putting it in
+    /// the head to be returned before the message.
+    fn synthesize_envelope(&mut self) -> io::Result<()> {
+        let mut entry = MboxEntry {
+            inner: &mut self.inner,
+            head: &mut self.head,
+        };
+        let envelope = entry.envelope()?;
+        if envelope.is_empty() || envelope.starts_with(b"From ") {
+            return Ok(());
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        let line = format!("From MAILER-DAEMON {}\n", envelope::format_asctime(now));
+        self.head.bytes.splice(..0, line.bytes());
+        self.head.synthesized = line.len();
+        Ok(())
+    }
 
-        if let Some(newline_idx) =
-            memmem::find(&self.buffer[self.ready_start..self.held_back], &MAGIC_WORD)
-        {
-            // the index returned by memmem::find is relative to the start of the slice
-            let absolute_idx = self.ready_start + newline_idx + 1;
-            self.ready_end = absolute_idx;
-            self.next_message_start = Some(absolute_idx);
+    /// Does what has to be done before the first message.
+    fn start(&mut self) -> io::Result<()> {
+        if self.strip_bom {
+            self.inner.skip_bom()?;
+        }
+        if self.trim_leading {
+            self.inner.skip_leading_whitespace()?;
+        }
+        // only now, so it's retried after an error
+        self.at_start = false;
+        Ok(())
+    }
+
+    /// Checks whether the stream starts the way an mbox does, to reject other input early: with a
+    /// `From ` line whose sender isn't empty and which contains no control characters (as a binary
+    /// file would). This may be wrong for an mbox with a very long first line. Only buffered bytes
+    /// are examined and nothing is consumed, so the reader can be used as normal afterwards.
+    pub fn looks_like_mbox(&mut self) -> io::Result<bool> {
+        if self.at_start {
+            self.start()?;
+        }
+        let available = if self.head.bytes.is_empty() {
+            self.inner.fill_buf()?
         } else {
-            self.ready_end = self.held_back;
+            &self.head.bytes[..]
+        };
+        let line = &available[..memchr(b'\n', available).unwrap_or(available.len())];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().any(|&b| b.is_ascii_control() && b != b'\t') {
+            return Ok(false);
         }
+        Ok(FromLine::parse(line).is_some_and(|from| !from.sender.is_empty()))
+    }
 
-        // search for `^>+From ` and erase one `>`, doing a copy within
-        if let Some(newline_off) = memmem::find(&self.buffer[self.ready_start..self.ready_end], b"\n>") {
-            let first_lt_idx = self.ready_start + newline_off + 1;
-            if let Some(from_off) = memmem::find(&self.buffer[first_lt_idx..self.ready_end], b"From ") {
-                let f_idx = first_lt_idx + from_off;
-                if self.buffer[first_lt_idx..f_idx].iter().all(|&b| b == b'>') {
-                    // this is expensive - possibly O(n) - but very rare
-                    self.buffer.remove(first_lt_idx);
-                    self.buffer.push(0);
-                    self.ready_end -= 1;
-                    self.held_back -= 1;
-                    self.buffer_end -= 1;
-                    self.next_message_start = self.next_message_start.map(|x| x - 1);
-                }
+    /// Reads the next message in full, for when the convenience is worth holding it in memory.
+    pub fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.next()? {
+            Some(mut entry) => {
+                let mut msg = Vec::new();
+                entry.read_to_end(&mut msg)?;
+                Ok(Some(msg))
             }
+            None => Ok(None),
         }
+    }
 
-        Ok(&self.buffer[self.ready_start..self.ready_end])
+    /// Sets the size the buffer may grow to. If it's no bigger than the initial capacity, the
+    /// buffer never grows.
+    pub fn max_buffer_capacity(mut self, max_capacity: usize) -> Self {
+        self.inner.max_capacity = max_capacity;
+        self
     }
 
-    fn consume(&mut self, amt: usize) {
-        assert!(amt <= (self.ready_end - self.ready_start));
-        self.ready_start += amt;
+    /// The current size of the buffer.
+    pub fn buffer_capacity(&self) -> usize {
+        self.inner.buffer.len()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Shrinks the buffer back towards its initial capacity, after it has grown for a large
+    /// message, so a long-lived reader doesn't hold on to that memory. Bytes which have been read
+    /// from the underlying stream but not yet returned are kept, so the buffer may stay bigger
+    /// than its initial capacity until they have been read. It grows again if need be.
+    pub fn shrink_buffer(&mut self) {
+        self.inner.shrink();
+    }
 
-    #[test]
-    fn dequoting() {
-        let input_lines: Vec<&[u8]> = vec![
-            b"From first@email.com",
-            b">From some other dude",
-            b"Here's a line of text",
-            b"From second@email.com",
-            b"Another line of text",
-            b">From yet another email address",
-            b"With more lines of text",
-        ];
-        let input: Vec<u8> = input_lines.join(&b"\r\n"[..]);
-        let mut reader = MboxReader::new(input.as_slice());
+    /// Calls `f` with each of the rest of the messages in turn, read in full into `buf`, which is
+    /// cleared first each time. Reusing one buffer for every message saves allocating for each, so
+    /// this suits tight loops over many small messages. Stops at the first error from `f` or from
+    /// reading, and returns it.
+    pub fn for_each_into(
+        &mut self,
+        buf: &mut Vec<u8>,
+        mut f: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        while let Some(mut entry) = self.next()? {
+            buf.clear();
+            entry.read_to_end(buf)?;
+            f(buf)?;
+        }
+        Ok(())
+    }
 
-        let first_message: io::Result<Vec<String>> = reader.next().unwrap().unwrap().lines().collect();
-        let first_message = first_message.unwrap();
-        assert_eq!(first_message.len(), 3);
-        assert_eq!(first_message[0].as_bytes(), input_lines[0]);
-        assert_eq!(first_message[1].as_bytes(), &input_lines[1][1..]);
-        assert_eq!(first_message[2].as_bytes(), input_lines[2]);
+    /// Skips the next `n` messages without returning them, or as many as are left, and returns how
+    /// many were skipped and how many bytes of the stream they took up, up to the start of the
+    /// message after them. Escaping `>` characters count, as in an index.
+    pub fn skip_messages(&mut self, n: usize) -> io::Result<(usize, u64)> {
+        let mut skipped = 0;
+        let mut bytes = 0;
+        while skipped < n {
+            if self.next()?.is_none() {
+                break;
+            }
+            let start = self.message_start();
+            bytes += self.skip_to_next_start()? - start;
+            skipped += 1;
+        }
+        Ok((skipped, bytes))
+    }
 
-        let second_message: io::Result<Vec<String>> = reader.next().unwrap().unwrap().lines().collect();
-        let second_message = second_message.unwrap();
-        assert_eq!(second_message.len(), 4);
-        assert_eq!(second_message[0].as_bytes(), input_lines[3]);
-        assert_eq!(second_message[1].as_bytes(), input_lines[4]);
-        assert_eq!(second_message[2].as_bytes(), &input_lines[5][1..]);
-        assert_eq!(second_message[3].as_bytes(), input_lines[6]);
-        assert!(reader.next().unwrap().is_none());
+    /// Returns an iterator over at most the next `n` messages, read in full. Nothing past the
+    /// `n`th message is read, and the reader can carry on from there once the iterator is dropped.
+    /// The iterator ends early at EOF or after an error.
+    pub fn take_messages(
+        &mut self,
+        n: usize,
+    ) -> impl Iterator<Item = io::Result<Vec<u8>>> + '_ {
+        let mut remaining = n;
+        std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            let next = self.next_message().transpose();
+            remaining = match next {
+                Some(Ok(_)) => remaining - 1,
+                _ => 0,
+            };
+            next
+        })
     }
 
-    #[test]
-    fn really_deep_quotes() {
-        let mut input: Vec<u8> = vec![0; DEFAULT_CAPACITY - 5];
-        input.extend_from_slice(b"\n>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>From Alice");
-        let reader = MessageBoundaryReader::new(input.as_slice());
-        assert_eq!(reader.lines().last().unwrap().unwrap(), ">>>>>>>>>>>>>>>>>>>>>>>>>>>>>>From Alice");
+    /// Returns an iterator over the rest of the messages, read in full, in batches of `batch_size`,
+    /// e.g. to insert them into a database a transaction at a time. The last batch may be smaller,
+    /// but is never empty. The iterator ends after an error, and the messages read as part of the
+    /// batch the error happened in are lost. Panics if `batch_size` is 0.
+    pub fn message_batches(
+        mut self,
+        batch_size: usize,
+    ) -> impl Iterator<Item = io::Result<Vec<Vec<u8>>>> {
+        assert!(batch_size > 0, "batch size must not be 0");
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let batch = self
+                .take_messages(batch_size)
+                .collect::<io::Result<Vec<_>>>();
+            done = !matches!(&batch, Ok(batch) if batch.len() == batch_size);
+            match batch {
+                Ok(batch) if batch.is_empty() => None,
+                batch => Some(batch),
+            }
+        })
     }
 
-    #[test]
-    fn one_byte_reads() {
-        // read one byte at a time and check it behaves properly
-        let input = b"From line1\nFrom line2";
-        let mut reader = MessageBoundaryReader::new(input.as_slice());
-        let mut buf: [u8; 1] = [0];
-        let mut full: Vec<u8> = Vec::with_capacity(input.len());
-        for _ in 0..11 {
-            assert_eq!(1, reader.read(&mut buf).unwrap());
-            full.push(buf[0]);
-        }
-        assert_eq!(0, reader.read(&mut buf).unwrap());
+    /// Returns an iterator over the rest of the messages, read in full without their envelope
+    /// lines, i.e. as the RFC 5322 messages a mail parser such as the `mailparse` crate's
+    /// `parse_mail` takes. A first message with no envelope line is returned whole. The iterator
+    /// ends after an error.
+    pub fn messages_without_envelopes(mut self) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = (|| {
+                let mut entry = match self.next()? {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+                let envelope_len = entry.from_line_len()?;
+                entry.consume(envelope_len);
+                let mut message = Vec::new();
+                entry.read_to_end(&mut message)?;
+                Ok(Some(message))
+            })()
+            .transpose();
+            done = !matches!(next, Some(Ok(_)));
+            next
+        })
+    }
+
+    /// Returns an iterator over the rest of the messages whose envelope date (see
+    /// [`FromLine::timestamp`]) is at or after `start` and before `end`, in seconds since the Unix
+    /// epoch, read in full. Messages without a usable date are skipped, as are the rest, after
+    /// reading just their envelope lines. The iterator ends after an error.
+    ///
+    /// The range is in Unix seconds rather than `chrono` `DateTime`s, since the crate doesn't
+    /// depend on `chrono` and envelope dates are parsed to seconds anyway. To select with a
+    /// `DateTime`, pass its `timestamp()`.
+    pub fn messages_between(
+        &mut self,
+        start: i64,
+        end: i64,
+    ) -> impl Iterator<Item = io::Result<Vec<u8>>> + '_ {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = self
+                .next_matching(|line| {
+                    line.and_then(FromLine::timestamp)
+                        .is_some_and(|date| (start..end).contains(&date))
+                })
+                .transpose();
+            done = !matches!(next, Some(Ok(_)));
+            next
+        })
+    }
+
+    /// Returns an iterator over the rest of the messages whose envelope line satisfies `pred`,
+    /// read in full. Messages without an envelope line are skipped, as are those `pred` rejects,
+    /// after reading just their envelope lines. The iterator ends after an error.
+    pub fn filter_by_from(
+        mut self,
+        pred: impl Fn(&FromLine) -> bool,
+    ) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = self
+                .next_matching(|line| line.is_some_and(&pred))
+                .transpose();
+            done = !matches!(next, Some(Ok(_)));
+            next
+        })
+    }
+
+    /// Reads the next message whose envelope line `pred` accepts, skipping the others.
+    fn next_matching(
+        &mut self,
+        mut pred: impl FnMut(Option<&FromLine>) -> bool,
+    ) -> io::Result<Option<Vec<u8>>> {
+        while let Some(mut entry) = self.next()? {
+            if pred(entry.from_line()?.as_ref()) {
+                let mut msg = Vec::new();
+                entry.read_to_end(&mut msg)?;
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Treats the entire stream as a single message terminated by EOF, for reading a lone
+    /// RFC 5322 message which has no `From ` line. Lines starting with `From ` or `>From ` are
+    /// returned as-is rather than splitting or being unescaped.
+    pub fn single_message(mut self, single: bool) -> Self {
+        self.inner.single_message = single;
+        self
+    }
+
+    /// Skips a UTF-8 byte order mark at the start of the stream, as written by some Windows tools,
+    /// so the first `From ` line is found. On by default; when off, it's returned as part of the
+    /// first message.
+    pub fn strip_bom(mut self, strip: bool) -> Self {
+        self.strip_bom = strip;
+        self
+    }
+
+    /// Skips any whitespace before the first message (after a byte order mark, if that is
+    /// stripped). Off by default, so the whitespace is returned as part of the first message, or
+    /// as a message of its own if it ends in a newline.
+    pub fn trim_leading(mut self, trim: bool) -> Self {
+        self.trim_leading = trim;
+        self
+    }
+
+    /// Skips the pseudo-message which some IMAP servers (e.g. Dovecot and UW-IMAP) keep at the start
+    /// of an mbox to hold mailbox metadata, recognised by an `X-IMAPbase` or `X-IMAP` header. This
+    /// is usually after `Date`, `From` and `Subject` headers, so isn't required to come first.
+    /// Only the first message is checked. The default is to return it like any other message.
+    pub fn skip_imap_header(mut self, skip: bool) -> Self {
+        self.skip_imap_header = skip;
+        self
+    }
+
+    fn first_is_imap_header(&mut self) -> io::Result<bool> {
+        let mut entry = MboxEntry {
+            inner: &mut self.inner,
+            head: &mut self.head,
+        };
+        Ok(unfold_headers(entry.head()?).iter().any(|(name, _)| {
+            name.eq_ignore_ascii_case(b"X-IMAPbase") || name.eq_ignore_ascii_case(b"X-IMAP")
+        }))
+    }
+
+    /// Skips messages which are empty, i.e. have nothing but whitespace after the envelope line, as
+    /// corrupt files with runs of bare `From ` lines have. Blank lines count as whitespace, so a
+    /// message which is only an envelope line and the blank line separating it from the next is
+    /// empty too. Off by default; when on, `next` never returns an empty message.
+    pub fn skip_empty(mut self, skip: bool) -> Self {
+        self.skip_empty = skip;
+        self
+    }
+
+    /// Gives a message which doesn't start with a `From ` line one, `From MAILER-DAEMON` and the
+    /// time it was read, for tools which need every message to have an envelope. Only the first
+    /// message can be missing one, when there is something other than a message at the start of
+    /// the stream. The line is returned by reads, and by `from_line`, as if it had been in the
+    /// stream, but offsets are only those of bytes which were. Off by default.
+    pub fn synthesize_missing_envelopes(mut self, synthesize: bool) -> Self {
+        self.synthesize_missing_envelopes = synthesize;
+        self
+    }
+
+    /// Makes `next` return an error on finding the first malformed message, for ingestion which
+    /// should fail rather than guess (see [`MboxError`] for what counts). An envelope line without
+    /// a sender or a parseable date is found when the message is moved to, and a missing blank line
+    /// or a body the wrong length for its `Content-Length` when it is moved past, so by the call to
+    /// `next` after the one which returned it, or the one which found the end of the stream. The
+    /// head of each message is read to check it. Reading can carry on after such an error, past
+    /// the malformed message. Off by default.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Accepts `From` followed by a tab as well as a space as the start of a message, for
+    /// malformed files which use `From\t`. The default is to require a single space.
+    pub fn lenient_separator(mut self, lenient: bool) -> Self {
+        self.inner.lenient_separator = lenient;
+        self
+    }
+
+    /// Sets whether the blank line which ends a message, just before the next `From ` line, is
+    /// returned as part of that message. The default is to return it, so the messages add up to
+    /// the whole file. Otherwise it is treated as a separator and dropped, which is what an
+    /// [`MboxWriter`] adds back after each message. Only a single empty line (`\n` or `\r\n`) right
+    /// before the boundary is dropped, and the last message in the stream keeps its blank line,
+    /// since nothing follows it.
+    pub fn keep_separator_line(mut self, keep: bool) -> Self {
+        self.inner.keep_separator_line = keep;
+        self
+    }
+
+    /// Drops the blank lines at the end of each message, which some mailers append, including the
+    /// one separating it from the next message, so every message ends with the last line which has
+    /// anything on it. Lines with nothing but spaces, tabs and `\r` count as blank. A message with
+    /// an empty body loses the blank line after its headers too, and a run of blank lines longer
+    /// than the buffer may only be partly dropped. Off by default.
+    pub fn trim_trailing_blank_lines(mut self, trim: bool) -> Self {
+        self.inner.trim_trailing_blank_lines = trim;
+        self
+    }
+
+    /// Sets how `From ` lines in message bodies were escaped when the file was written, so they
+    /// can be unescaped. The default is [`Dialect::Mboxrd`], which also reads mboxo files correctly
+    /// except for bodies which originally contained `>From ` lines.
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.inner.dialect = dialect;
+        self
+    }
+
+    /// Calls `hook` with each [`ReadEvent`] as the reader refills its buffer and looks for
+    /// boundaries, e.g. to log them while debugging. Replaces any hook set before.
+    pub fn on_event(mut self, hook: impl FnMut(ReadEvent) + Send + 'static) -> Self {
+        self.inner.on_event = Some(Box::new(hook));
+        self
+    }
+
+    /// Swaps the underlying reader for `new`, returning the old one. Bytes which have already been
+    /// read from the old reader stay buffered and will be returned before anything from `new`.
+    ///
+    /// `new` must be positioned at the same logical offset as the old reader, i.e. immediately
+    /// after the last byte the old reader returned. This is meant for resuming a stream (e.g. over
+    /// a fresh network connection) without losing the messages which are already in flight.
+    pub fn replace_inner(&mut self, new: R) -> R {
+        std::mem::replace(&mut self.inner.inner, new)
+    }
+
+    /// Returns whether the last message in the stream ended with a newline, so a writer can
+    /// reproduce the file exactly. Every other message necessarily does, since a boundary starts
+    /// with one. Returns `None` until the last message has been read or skipped to the end, and for
+    /// an empty stream.
+    pub fn last_message_had_trailing_newline(&mut self) -> io::Result<Option<bool>> {
+        if self.head.unread() > 0 || !self.inner.eof()? {
+            return Ok(None);
+        }
+        Ok(self.inner.last_byte.map(|b| b == b'\n'))
+    }
+
+    /// Returns the offset of the newline which ends the message most recently returned by `next`,
+    /// i.e. the byte before the next `From ` line, or the length of the stream if it was the last
+    /// message. Whatever is left unread of that message is skipped. Offsets refer to the underlying
+    /// stream, so they count escaping `>` characters even though those aren't returned.
+    ///
+    /// Returns an `InvalidInput` error if the reader is positioned on a message which `next`
+    /// hasn't returned yet, including before the first call to `next`.
+    pub fn current_boundary_offset(&mut self) -> io::Result<u64> {
+        if self.pending {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no current message",
+            ));
+        }
+        self.head.discard();
+        self.inner.skip_message()?;
+        Ok(match self.inner.next_message_start {
+            Some(start) => self.inner.offset_of(start) - 1,
+            None => self.inner.end_offset(),
+        })
+    }
+
+    /// Distributes the rest of the messages round-robin between `n` new mbox files, written with
+    /// the reader's dialect to the writers returned by `make_writer` (called with 0 to `n - 1`),
+    /// and returns how many messages went to each. Every message must start with a `From ` line,
+    /// otherwise an `InvalidData` error is returned; if `n` is 0, an `InvalidInput` error is.
+    pub fn split_into<W: Write>(
+        &mut self,
+        n: usize,
+        mut make_writer: impl FnMut(usize) -> io::Result<W>,
+    ) -> io::Result<Vec<usize>> {
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't split into no files",
+            ));
+        }
+        let dialect = self.inner.dialect;
+        let mut writers = (0..n)
+            .map(|i| Ok(MboxWriter::with_dialect(make_writer(i)?, dialect)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut counts = vec![0; n];
+        let mut shard = 0;
+        while let Some(message) = self.next_message()? {
+            writers[shard].append_read_message(&message, self.inner.keep_separator_line)?;
+            counts[shard] += 1;
+            shard = (shard + 1) % n;
+        }
+        for writer in &mut writers {
+            writer.flush()?;
+        }
+        Ok(counts)
+    }
+
+    /// Reads the rest of the stream and returns the offset of each message, as for
+    /// `read_message_at`. Bodies are skipped without being returned.
+    pub fn build_index(&mut self) -> io::Result<Vec<u64>> {
+        let mut offsets = Vec::new();
+        while self.next()?.is_some() {
+            offsets.push(self.last_start.unwrap());
+        }
+        Ok(offsets)
+    }
+
+    /// Indexes the rest of the stream, recording where each message starts and ends and how long
+    /// its envelope and headers are. Offsets are those of the underlying stream, as for
+    /// `read_message_at`.
+    pub fn build_index_detailed(&mut self) -> io::Result<MboxIndex> {
+        let mut entries = Vec::new();
+        while let Some(mut entry) = self.next()? {
+            entry.head()?;
+            let offset = self.message_start();
+            let header_len = self.inner.position() - offset;
+            let end = self.skip_to_next_start()?;
+            entries.push(IndexEntry {
+                offset,
+                header_len,
+                len: end - offset,
+            });
+        }
+        Ok(MboxIndex::new(entries))
+    }
+
+    /// Returns an iterator over the rest of the messages, read in full, each with the range of the
+    /// stream it came from, up to the start of the next. The ranges are those an index would give
+    /// (see [`IndexEntry::range`]), so they count escaping `>` characters which aren't in the
+    /// messages. The iterator ends after an error.
+    pub fn messages_with_ranges(
+        mut self,
+    ) -> impl Iterator<Item = io::Result<(MessageRange, Vec<u8>)>> {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = (|| {
+                let mut message = Vec::new();
+                match self.next()? {
+                    Some(mut entry) => entry.read_to_end(&mut message)?,
+                    None => return Ok(None),
+                };
+                let start = self.last_start.unwrap();
+                let end = self.skip_to_next_start()?;
+                Ok(Some((MessageRange { start, end }, message)))
+            })()
+            .transpose();
+            done = !matches!(next, Some(Ok(_)));
+            next
+        })
+    }
+
+    /// Returns the offset where the current message starts, allowing for any of its head which has
+    /// been read but not yet returned.
+    fn message_start(&self) -> u64 {
+        self.inner.position() - self.head.stream_unread() as u64
+    }
+
+    /// Skips the rest of the current message and returns the offset where the next one starts, or
+    /// the length of the stream if there isn't one.
+    fn skip_to_next_start(&mut self) -> io::Result<u64> {
+        self.head.discard();
+        self.inner.skip_message()?;
+        Ok(match self.inner.next_message_start {
+            Some(start) => self.inner.offset_of(start),
+            None => self.inner.end_offset(),
+        })
+    }
+
+    /// Moves to the next message and returns its envelope and header lines, through the blank line
+    /// which ends them, without reading the body. This is for header-only scans which shouldn't
+    /// allocate per message: the bytes are borrowed from the reader's buffer when they are all in
+    /// it, and otherwise copied into a buffer which is reused for every message.
+    ///
+    /// This can't be an `Iterator` for the same reason `next` can't.
+    pub fn next_header_block(&mut self) -> io::Result<Option<&[u8]>> {
+        if self.next()?.is_none() {
+            return Ok(None);
+        }
+        if !self.head.complete && self.head.ahead.is_empty() {
+            if let Some(len) = head_len(self.inner.fill_buf()?) {
+                let start = self.inner.ready_start;
+                self.inner.consume(len);
+                return Ok(Some(&self.inner.buffer[start..start + len]));
+            }
+        }
+        let mut entry = MboxEntry {
+            inner: &mut self.inner,
+            head: &mut self.head,
+        };
+        entry.skip_head()?;
+        Ok(Some(&self.head.bytes))
+    }
+
+    /// Skips forward to the next message whose `header` (matched case-insensitively) contains
+    /// `needle`. Returns true if one was found, in which case the following call to `next` returns
+    /// that message in full. Returns false if EOF was reached first.
+    ///
+    /// Only the head of each message is read; the rest is skipped without being copied.
+    pub fn skip_to_matching(&mut self, header: &[u8], needle: &[u8]) -> io::Result<bool> {
+        loop {
+            let matched = match self.next()? {
+                None => return Ok(false),
+                Some(mut entry) => unfold_headers(entry.head()?).iter().any(|(name, value)| {
+                    name.eq_ignore_ascii_case(header) && memmem::find(value, needle).is_some()
+                }),
+            };
+            if matched {
+                // it will be returned again
+                self.messages_returned -= 1;
+                self.pending = true;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Reads and parses every remaining message. Everything is loaded into memory at once, so this
+    /// is only suitable for small mailboxes.
+    pub fn parse_all(&mut self) -> io::Result<Vec<ParsedMessage>> {
+        let mut messages = Vec::new();
+        while let Some(mut entry) = self.next()? {
+            let from = entry.from_line()?;
+            let headers = unfold_headers(entry.head()?);
+            entry.skip_head()?;
+            let mut body = Vec::new();
+            entry.read_to_end(&mut body)?;
+            messages.push(ParsedMessage {
+                from,
+                headers,
+                body,
+            });
+        }
+        Ok(messages)
+    }
+
+    /// Counts the non-overlapping occurrences of `needle` in the bodies of every remaining message,
+    /// reading them in pieces rather than loading whole messages. Headers aren't searched. An empty
+    /// `needle` matches nothing.
+    pub fn count_in_bodies(&mut self, needle: &[u8]) -> io::Result<usize> {
+        if needle.is_empty() {
+            return Ok(0);
+        }
+        let finder = memmem::Finder::new(needle);
+        let mut count = 0;
+        // the end of the previous piece, in case a match starts there
+        let mut window: Vec<u8> = Vec::new();
+        while let Some(mut entry) = self.next()? {
+            entry.skip_head()?;
+            window.clear();
+            loop {
+                let piece = entry.fill_buf()?;
+                if piece.is_empty() {
+                    break;
+                }
+                window.extend_from_slice(piece);
+                let len = piece.len();
+                entry.consume(len);
+
+                let mut last_end = 0;
+                for i in finder.find_iter(&window) {
+                    count += 1;
+                    last_end = i + needle.len();
+                }
+                let keep_from = window.len().saturating_sub(needle.len() - 1).max(last_end);
+                window.drain(..keep_from);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Collects the `Message-ID`, `In-Reply-To` and `References` headers of every remaining
+    /// message, in order. Message bodies are skipped.
+    pub fn extract_threading_headers(&mut self) -> io::Result<Vec<ThreadingInfo>> {
+        let mut threads = Vec::new();
+        while let Some(mut entry) = self.next()? {
+            threads.push(ThreadingInfo::from_headers(&unfold_headers(entry.head()?)));
+        }
+        Ok(threads)
+    }
+
+    /// Counts the remaining messages from each sender, taken from `source`, reading only their
+    /// heads. Senders are compared byte for byte, so differently capitalised addresses are counted
+    /// apart. Messages without a sender (no envelope line, or no `From:` header) aren't counted.
+    pub fn count_by_sender(
+        &mut self,
+        source: SenderSource,
+    ) -> io::Result<HashMap<Vec<u8>, usize>> {
+        let mut counts = HashMap::new();
+        while let Some(head) = self.next_header_block()? {
+            let sender = match source {
+                SenderSource::Envelope => {
+                    let envelope = &head[..memchr(b'\n', head).map_or(head.len(), |i| i + 1)];
+                    FromLine::parse(envelope).map(|from| from.sender)
+                }
+                SenderSource::FromHeader => unfold_headers(head)
+                    .into_iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(b"From"))
+                    .map(|(_, value)| header_address(&value).to_vec()),
+            };
+            if let Some(sender) = sender {
+                *counts.entry(sender).or_insert(0) += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Finds every `Message-ID` which more than one of the remaining messages has, returning each
+    /// with the offsets where those messages start, in the order the IDs first appear. Only the
+    /// heads of messages are read. Messages without a `Message-ID` are ignored.
+    pub fn find_duplicate_message_ids(&mut self) -> io::Result<Vec<(Vec<u8>, Vec<u64>)>> {
+        let mut offsets: HashMap<Vec<u8>, Vec<u64>> = HashMap::new();
+        let mut order = Vec::new();
+        while let Some(head) = self.next_header_block()? {
+            let id = match ThreadingInfo::from_headers(&unfold_headers(head)).message_id {
+                Some(id) if !id.is_empty() => id,
+                _ => continue,
+            };
+            let offset = self.last_start.unwrap();
+            offsets
+                .entry(id)
+                .or_insert_with_key(|id| {
+                    order.push(id.clone());
+                    Vec::new()
+                })
+                .push(offset);
+        }
+        Ok(order
+            .into_iter()
+            .filter_map(|id| {
+                let starts = offsets.remove(&id).unwrap();
+                (starts.len() > 1).then_some((id, starts))
+            })
+            .collect())
+    }
+}
+
+impl MboxReader<io::StdinLock<'static>> {
+    /// Reads an mbox piped to standard input, which stays locked for the life of the reader. Stdin
+    /// can't seek, so only the streaming methods are useful.
+    ///
+    /// ```no_run
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut reader = mbox_indexer::MboxReader::from_stdin();
+    /// let mut count = 0;
+    /// while reader.next()?.is_some() {
+    ///     count += 1;
+    /// }
+    /// println!("{} messages", count);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_stdin() -> Self {
+        MboxReader::from_pipe(io::stdin().lock())
+    }
+}
+
+impl<R: BufRead> MboxReader<R> {
+    /// What [`MboxReader::from_stdin`] does with the locked stdin, so it can be tested with any
+    /// other stream which can't be rewound.
+    pub(crate) fn from_pipe(inner: R) -> Self {
+        MboxReader::new(inner)
+    }
+}
+
+impl<D: Read> MboxReader<D> {
+    /// Reads a bzip2-compressed mbox from `inner`, decompressed by the reader `decoder` makes of
+    /// it, e.g. `bzip2::read::BzDecoder::new` from the `bzip2` crate. (There is no decompressor in
+    /// this crate.) `inner` is checked first for the bzip2 header, and an `InvalidData` error is
+    /// returned if it doesn't start with one; what was read to check is given back to the decoder
+    /// ahead of the rest. As with any stream which can't seek, only the streaming methods are
+    /// useful.
+    pub fn new_bzip2<R: Read>(
+        mut inner: R,
+        decoder: impl FnOnce(io::Chain<io::Cursor<Vec<u8>>, R>) -> D,
+    ) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(4);
+        inner.by_ref().take(4).read_to_end(&mut header)?;
+        if !matches!(*header, [b'B', b'Z', b'h', b'1'..=b'9']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not bzip2-compressed",
+            ));
+        }
+        Ok(MboxReader::new(decoder(io::Cursor::new(header).chain(inner))))
+    }
+}
+
+impl<R: Read> MboxReader<io::Take<R>> {
+    /// Reads an mbox which is the next `length` bytes of `inner`, e.g. one embedded in an archive,
+    /// without reading anything past it. Messages end at the bound as they would at EOF.
+    pub fn new_bounded(inner: R, length: u64) -> Self {
+        MboxReader::new(inner.take(length))
+    }
+
+    /// Reads no more than `max_bytes` from `inner`, as a safeguard against a stream which doesn't
+    /// end, e.g. untrusted input from a pipe. Reaching the limit looks like EOF, so a message cut
+    /// off by it is returned truncated, as the last. This is [`MboxReader::new_bounded`], for when
+    /// the bound is a limit rather than a known length.
+    pub fn with_total_limit(inner: R, max_bytes: u64) -> Self {
+        Self::new_bounded(inner, max_bytes)
+    }
+}
+
+impl<R: Read + Seek> MboxReader<R> {
+    /// Skips the unread bodies of messages with a `Content-Length` header, as mboxcl files have,
+    /// by seeking past that many bytes instead of scanning for the next `From ` line. This only
+    /// happens where the header is borne out: the body is followed by a `From ` line, after at
+    /// most a blank line. Otherwise, and for the last message, the body is scanned as usual, as
+    /// are bodies which are read. Off by default.
+    pub fn content_length(mut self, use_content_length: bool) -> Self {
+        self.content_length_seek = use_content_length.then_some(R::seek);
+        self
+    }
+
+    /// Creates a reader which continues from a [`ResumeToken`] taken from an earlier reader of the
+    /// same file. Returns an `InvalidData` error if the file's length has changed since, in which
+    /// case it should be rescanned from the start.
+    pub fn resume(mut inner: R, token: &ResumeToken) -> io::Result<Self> {
+        if inner.seek(SeekFrom::End(0))? != token.file_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file has changed since the resume token was taken",
+            ));
+        }
+        let mut reader = Self::new(inner);
+        reader.seek_to_message(token.offset)?;
+        reader.messages_returned = token.message_number;
+        Ok(reader)
+    }
+
+    /// Returns a token recording the start of the next message to be returned by `next`, for
+    /// [`MboxReader::resume`]. Whatever is left unread of the current message is skipped.
+    pub fn resume_token(&mut self) -> io::Result<ResumeToken> {
+        let offset = if self.pending {
+            self.message_start()
+        } else {
+            self.skip_to_next_start()?
+        };
+        let inner = &mut self.inner.inner;
+        let saved = inner.stream_position()?;
+        let file_len = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(saved))?;
+        Ok(ResumeToken {
+            offset,
+            message_number: self.messages_returned,
+            file_len,
+        })
+    }
+
+    /// Reads the last message in the stream, or returns `None` if it's empty, without reading the
+    /// rest: the stream is searched backward from the end for the last boundary, a chunk at a
+    /// time. A stream with no boundary is one message, starting at offset 0. The reader is left at
+    /// the end of the stream.
+    pub fn last_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let start = if self.inner.single_message {
+            0
+        } else {
+            self.last_boundary()?
+        };
+        self.seek_to_message(start)?;
+        self.next_message()
+    }
+
+    /// Returns the offset of the last message start found by searching backward from the end of
+    /// the stream, or 0 if there is none.
+    fn last_boundary(&mut self) -> io::Result<u64> {
+        let separator = &self.inner.separator;
+        let (&last, prefix) = separator.split_last().unwrap();
+        let lenient = self.inner.lenient_separator;
+        let inner = &mut self.inner.inner;
+        let len = inner.seek(SeekFrom::End(0))?;
+        let mut chunk = Vec::with_capacity(DEFAULT_CAPACITY + separator.len());
+        let mut end = len;
+        while end > 0 {
+            // each chunk overlaps the one after it by all but a byte of a separator, so one which
+            // straddles them is found in this one
+            let chunk_start = end.saturating_sub(DEFAULT_CAPACITY as u64);
+            let overlap = (len - end).min(separator.len() as u64 - 1);
+            inner.seek(SeekFrom::Start(chunk_start))?;
+            chunk.clear();
+            inner
+                .by_ref()
+                .take(end - chunk_start + overlap)
+                .read_to_end(&mut chunk)?;
+            let found = memmem::rfind_iter(&chunk, prefix).find(|&i| {
+                let after = chunk.get(i + prefix.len()).copied();
+                after == Some(last) || lenient && after == Some(b'\t')
+            });
+            if let Some(i) = found {
+                return Ok(chunk_start + i as u64 + 1);
+            }
+            end = chunk_start;
+        }
+        Ok(0)
+    }
+
+    /// Reads the message starting at `offset`, which must be the start of its `From ` line (e.g.
+    /// from an index). The reader is left after that message, so `next` continues with the one
+    /// following it.
+    pub fn read_message_at(&mut self, offset: u64) -> io::Result<Vec<u8>> {
+        self.seek_to_message(offset)?;
+        Ok(self.next_message()?.unwrap_or_default())
+    }
+
+    /// Returns the headers of the message starting at `offset` (e.g. from an index), unfolded as
+    /// by [`unfold_headers`], without reading its body. The following call to `next` returns the
+    /// message after it.
+    pub fn headers_at(&mut self, offset: u64) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.seek_to_message(offset)?;
+        match self.next()? {
+            Some(mut entry) => Ok(unfold_headers(entry.head()?)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Appends the message in `range` (from an index of the same file) to `buf`, reserving room for
+    /// it first so `buf` doesn't have to grow as it's read. The message is as `next` would return
+    /// it, so it's shorter than the range if any escaping `>` were removed. The reader is left
+    /// after that message.
+    pub fn read_range(&mut self, range: &MessageRange, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.reserve(range.len() as usize);
+        self.seek_to_message(range.start)?;
+        if let Some(mut entry) = self.next()? {
+            entry.read_to_end(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Hashes the whole stream from offset 0, for telling cheaply whether a file has changed since
+    /// it was indexed (see [`MboxReader::build_index_with_fingerprint`]). The stream is read in
+    /// chunks and then sought back to where it was, so the reader carries on as if this hadn't
+    /// been called.
+    pub fn file_fingerprint(&mut self) -> io::Result<u64> {
+        let inner = &mut self.inner.inner;
+        let pos = inner.stream_position()?;
+        inner.seek(SeekFrom::Start(0))?;
+        let mut chunk = vec![0; DEFAULT_CAPACITY];
+        let mut hash = FNV_OFFSET_BASIS;
+        let result = loop {
+            match inner.read(&mut chunk) {
+                Ok(0) => break Ok(hash),
+                Ok(n) => hash = fnv1a_update(hash, &chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        inner.seek(SeekFrom::Start(pos))?;
+        result
+    }
+
+    /// Indexes the rest of the stream as `build_index_detailed` does, recording the
+    /// `file_fingerprint` with it so [`MboxIndex::is_current`] can tell when the file changes.
+    pub fn build_index_with_fingerprint(&mut self) -> io::Result<MboxIndex> {
+        let fingerprint = self.file_fingerprint()?;
+        let mut index = self.build_index_detailed()?;
+        index.set_fingerprint(fingerprint);
+        Ok(index)
+    }
+
+    /// Returns an iterator over the messages from `offset` on, read in full, each with its offset
+    /// as for `read_message_at`. `offset` must be where a message starts: for catching up with a
+    /// file which has only been appended to, the length it had when it was last read. The
+    /// iterator ends after an error.
+    pub fn messages_since(
+        &mut self,
+        offset: u64,
+    ) -> impl Iterator<Item = io::Result<(u64, Vec<u8>)>> + '_ {
+        let mut seek_to = Some(offset);
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let message = match seek_to.take() {
+                Some(offset) => self.seek_to_message(offset).and_then(|()| self.next_message()),
+                None => self.next_message(),
+            };
+            let next = message
+                .map(|message| message.map(|message| (self.last_start.unwrap(), message)))
+                .transpose();
+            done = !matches!(next, Some(Ok(_)));
+            next
+        })
+    }
+
+    /// Moves to message `n` (counting from 0) of `index`, which must have been built from the same
+    /// file, so the next call to `next` returns it. Returns false, leaving the reader where it was,
+    /// if the index has no message `n`.
+    pub fn goto(&mut self, index: &MboxIndex, n: usize) -> io::Result<bool> {
+        let entry = match index.get(n) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        self.seek_to_message(entry.offset)?;
+        self.messages_returned = n;
+        Ok(true)
+    }
+
+    /// Reads the rest of the stream and returns the offset of each message, as for
+    /// `read_message_at`, ordered by the date on its envelope line (see [`FromLine::timestamp`]),
+    /// oldest first. Messages with the same date stay in file order, as do those whose date is
+    /// missing or can't be parsed, which come last.
+    ///
+    /// Dates are compared as the Unix seconds `FromLine::timestamp` gives rather than as `chrono`
+    /// `DateTime`s, which would sort the same, so no `chrono` feature is needed.
+    pub fn build_index_sorted_by_date(&mut self) -> io::Result<Vec<u64>> {
+        let mut dated = Vec::new();
+        while let Some(mut entry) = self.next()? {
+            let date = entry.from_line()?.and_then(|line| line.timestamp());
+            dated.push((date, self.last_start.unwrap()));
+        }
+        dated.sort_by_key(|&(date, _)| (date.is_none(), date));
+        Ok(dated.into_iter().map(|(_, offset)| offset).collect())
+    }
+
+    /// Reads the rest of the stream and returns the offset of each message, as for
+    /// `read_message_at`, with the sender from its envelope line (see [`FromLine::sender`]),
+    /// converted lossily to UTF-8. Only the envelope lines are parsed. A first message with no
+    /// envelope line has an empty sender.
+    pub fn index_with_senders(&mut self) -> io::Result<Vec<(u64, String)>> {
+        let mut senders = Vec::new();
+        while let Some(mut entry) = self.next()? {
+            let sender = entry.from_line()?.map(|line| line.sender).unwrap_or_default();
+            let sender = String::from_utf8_lossy(&sender).into_owned();
+            senders.push((self.last_start.unwrap(), sender));
+        }
+        Ok(senders)
+    }
+
+    /// Returns the length in bytes of the longest message left to read, up to the start of the
+    /// next, or 0 if there are none, so a caller can decide whether to read messages whole or
+    /// stream them. The reader is then moved back to the next message to be returned by `next`;
+    /// whatever is left unread of the current message is skipped.
+    pub fn max_message_size(&mut self) -> io::Result<u64> {
+        let offset = if self.pending {
+            self.message_start()
+        } else {
+            self.skip_to_next_start()?
+        };
+        let messages_returned = self.messages_returned;
+        let index = self.build_index_detailed()?;
+        self.seek_to_message(offset)?;
+        self.messages_returned = messages_returned;
+        Ok(index.entries().iter().map(|entry| entry.len).max().unwrap_or(0))
+    }
+
+    /// Estimates how many messages are left to read, for a progress indicator. The average size
+    /// of the first few messages in the file is extrapolated over the unread bytes, so this is only
+    /// as accurate as those messages are typical. The reader's position is unchanged.
+    pub fn estimate_remaining(&mut self) -> io::Result<usize> {
+        let position = self.message_start();
+
+        let inner = &mut self.inner.inner;
+        let saved = inner.stream_position()?;
+        let total = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(0))?;
+        let sample = sample_message_size(inner, ESTIMATE_SAMPLE_MESSAGES);
+        inner.seek(SeekFrom::Start(saved))?;
+        let (sampled_bytes, sampled_messages) = sample?;
+
+        let remaining = total.saturating_sub(position);
+        if remaining == 0 || sampled_messages == 0 {
+            return Ok(0);
+        }
+        let estimate = remaining as f64 * sampled_messages as f64 / sampled_bytes as f64;
+        Ok(estimate.round().max(1.0) as usize)
+    }
+
+    /// Returns whatever follows the body of the last message, such as a footer appended by some
+    /// tool. An mbox has no end marker, so this can only be told apart from the body when the last
+    /// message has a `Content-Length` header: the trailing bytes are those after that many bytes of
+    /// body and the blank line which may follow them. Without the header, or if the body is no
+    /// longer than it says, there are none.
+    ///
+    /// The trailing bytes are still returned by `next` as part of the last message, since they
+    /// can't be recognised until all of it has been read. Returns an `InvalidInput` error unless
+    /// the whole stream has been read.
+    pub fn trailing_bytes(&mut self) -> io::Result<Vec<u8>> {
+        if self.pending || !self.inner.eof()? || self.head.unread() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the stream hasn't been read to the end",
+            ));
+        }
+        let start = match self.last_start {
+            Some(start) => start,
+            None => return Ok(Vec::new()),
+        };
+        let end = self.inner.end_offset();
+        let messages_returned = self.messages_returned;
+
+        self.seek_to_message(start)?;
+        let content_length = match self.next()? {
+            Some(mut message) => unfold_headers(message.head()?)
+                .into_iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(b"Content-Length"))
+                .and_then(|(_, value)| index::parse_length(&value)),
+            None => None,
+        };
+        let body_start = self.inner.position();
+        let mut trailing = Vec::new();
+        if let Some(body_end) = content_length.map(|length| body_start + length) {
+            if body_end < end {
+                let inner = &mut self.inner.inner;
+                inner.seek(SeekFrom::Start(body_end))?;
+                inner.take(end - body_end).read_to_end(&mut trailing)?;
+            }
+        }
+        let separator = [&b"\r\n"[..], b"\n"]
+            .into_iter()
+            .find(|separator| trailing.starts_with(separator))
+            .map_or(0, |separator| separator.len());
+        trailing.drain(..separator);
+
+        self.seek_to_message(end)?;
+        self.pending = false;
+        self.messages_returned = messages_returned;
+        self.last_start = Some(start);
+        Ok(trailing)
+    }
+
+    fn seek_to_message(&mut self, offset: u64) -> io::Result<()> {
+        self.inner.seek_to(offset)?;
+        self.head.clear();
+        self.end_check = None;
+        self.pending = true;
+        self.at_start = offset == 0;
+        Ok(())
+    }
+}
+
+const ESTIMATE_SAMPLE_MESSAGES: usize = 100;
+
+/// Reads from the start of a stream until `messages` boundaries have been seen, returning how many
+/// bytes that took and how many messages they held (fewer than `messages` at EOF).
+fn sample_message_size<R: Read>(inner: &mut R, messages: usize) -> io::Result<(u64, usize)> {
+    let mut buffer = vec![0; DEFAULT_CAPACITY];
+    // bytes carried over from the previous read, so a boundary split between reads is found
+    let mut carried = 0;
+    let mut offset: u64 = 0;
+    let mut found = 0;
+    loop {
+        let read = inner.read(&mut buffer[carried..])?;
+        if read == 0 {
+            let total = offset + carried as u64;
+            if total > 0 {
+                found += 1;
+            }
+            return Ok((total, found));
+        }
+        let filled = carried + read;
+        let mut start = 0;
+        while let Some(i) = find_boundary(&buffer[start..filled]) {
+            start += i;
+            found += 1;
+            if found == messages {
+                return Ok((offset + start as u64, found));
+            }
+        }
+        carried = (MAGIC_WORD.len() - 1).min(filled - start);
+        buffer.copy_within(filled - carried..filled, 0);
+        offset += (filled - carried) as u64;
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `std`'s hashers is guaranteed to give the same result everywhere.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_update(FNV_OFFSET_BASIS, bytes)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Continues an FNV-1a hash of some bytes with the bytes which follow them.
+fn fnv1a_update(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Returns the first address in an address header value like `Name <address>, ...`.
+fn header_address(value: &[u8]) -> &[u8] {
+    if let Some(open) = memchr(b'<', value) {
+        if let Some(close) = memchr(b'>', &value[open..]) {
+            return &value[open + 1..open + close];
+        }
+    }
+    let first = value.split(|&b| b == b',').next().unwrap();
+    first.trim_ascii()
+}
+
+/// Returns the length of the lines at the start of `bytes` up to and including the first empty
+/// one, the way `MboxEntry::head` reads them, or `None` if `bytes` doesn't contain an empty line.
+fn head_len(bytes: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let line_len = memchr(b'\n', &bytes[len..])? + 1;
+        let line = &bytes[len..len + line_len];
+        len += line_len;
+        if line == b"\n" || line == b"\r\n" {
+            return Some(len);
+        }
+    }
+}
+
+impl<'a, R: Read> MboxEntry<'a, R> {
+    /// Reads the envelope and header lines, through the blank line which ends them, and returns
+    /// them. They will still be returned by subsequent reads. This should be called before reading
+    /// from the entry, since only the bytes from the current position onward are examined.
+    fn head(&mut self) -> io::Result<&[u8]> {
+        if !self.head.complete {
+            loop {
+                let start = self.head.bytes.len();
+                if self.read_head_line()? == 0 {
+                    break;
+                }
+                let line = &self.head.bytes[start..];
+                if line == b"\n" || line == b"\r\n" {
+                    break;
+                }
+            }
+            self.head.complete = true;
+        }
+        Ok(&self.head.bytes)
+    }
+
+    /// Reads just the envelope line into the head, if it hasn't been read yet, and returns it. The
+    /// rest of the head can still be read after it by `head`.
+    fn envelope(&mut self) -> io::Result<&[u8]> {
+        if self.head.bytes.is_empty() && !self.head.complete {
+            self.read_head_line()?;
+            // `head` would stop at a blank line, so the head is a blank line and nothing more
+            if self.head.bytes == b"\n" || self.head.bytes == b"\r\n" {
+                self.head.complete = true;
+            }
+        }
+        let head = &self.head.bytes;
+        Ok(&head[..memchr(b'\n', head).map_or(head.len(), |i| i + 1)])
+    }
+
+    /// Reads one line onto the end of the head, taking it from the bytes looked ahead at first, and
+    /// returns its length.
+    fn read_head_line(&mut self) -> io::Result<usize> {
+        let ahead = &mut self.head.ahead;
+        if ahead.is_empty() {
+            return self.read_head_line_from_stream();
+        }
+        // the bytes keep their indices in `escapes`, since they stay in the same order
+        let len = memchr(b'\n', ahead).map_or(ahead.len(), |i| i + 1);
+        self.head.bytes.extend(ahead.drain(..len));
+        if self.head.bytes.ends_with(b"\n") {
+            Ok(len)
+        } else {
+            Ok(len + self.read_head_line_from_stream()?)
+        }
+    }
+
+    /// Reads the rest of a line from the stream onto the end of the head, noting whether an
+    /// escape was removed from it. One can only be at the start of a line, so there is at most
+    /// one, and if so the line took up a byte more of the stream than was read. If nothing is
+    /// read, the reader may instead have moved past bytes dropped before the next message.
+    fn read_head_line_from_stream(&mut self) -> io::Result<usize> {
+        let before = self.inner.position();
+        let len = self.inner.read_until(b'\n', &mut self.head.bytes)?;
+        let skipped = (self.inner.position() - before) as usize - len;
+        if len == 0 {
+            self.head.dropped += skipped;
+        } else if skipped > 0 {
+            self.head.add_escapes(len, std::iter::once(0));
+        }
+        Ok(len)
+    }
+
+    /// Moves past the envelope and headers, so the next read starts at the body.
+    fn skip_head(&mut self) -> io::Result<()> {
+        self.head()?;
+        self.head.pos = self.head.bytes.len();
+        Ok(())
+    }
+
+    /// Reads the body, i.e. everything after the envelope and headers, decoding it as UTF-8 with
+    /// invalid sequences replaced. A message with no body gives an empty string. No transfer
+    /// encoding is undone, so this is only readable for plain text bodies.
+    pub fn body_to_string_lossy(&mut self) -> io::Result<String> {
+        self.skip_head()?;
+        let mut body = Vec::new();
+        self.read_to_end(&mut body)?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Skips the body, i.e. everything after the envelope and headers, and returns its length. This
+    /// is its length in the stream, like the lengths in an index (see [`IndexEntry`]): escaping
+    /// `>` characters count, as does the blank line before the next message even if it isn't
+    /// returned. A message with no blank line after its headers has no body, so gives 0. This
+    /// should be called before reading from the entry, like `body_to_string_lossy`.
+    pub fn body_len(&mut self) -> io::Result<u64> {
+        self.skip_head()?;
+        // anything looked ahead at is body
+        let start = self.inner.position() - self.head.stream_unread() as u64;
+        self.head.discard();
+        self.inner.skip_message()?;
+        let end = match self.inner.next_message_start {
+            Some(next_start) => self.inner.offset_of(next_start),
+            None => self.inner.end_offset(),
+        };
+        Ok(end - start)
+    }
+
+    /// Returns a reader for the body which undoes quoted-printable encoding if the message's
+    /// `Content-Transfer-Encoding` says it is used, and otherwise returns the body unchanged. Only
+    /// the top-level header is consulted, so the parts of a multipart message aren't decoded.
+    #[cfg(feature = "quoted-printable")]
+    pub fn quoted_printable_reader(mut self) -> io::Result<QuotedPrintableReader<Self>> {
+        let encoded = unfold_headers(self.head()?).iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case(b"Content-Transfer-Encoding")
+                && value.trim_ascii().eq_ignore_ascii_case(b"quoted-printable")
+        });
+        self.skip_head()?;
+        Ok(QuotedPrintableReader::new(self, encoded))
+    }
+
+    /// Returns a reader for the body which decodes it if the message's `Content-Transfer-Encoding`
+    /// says it is base64, and otherwise returns the body unchanged, e.g. to extract a single
+    /// binary attachment sent as the whole message. As for `quoted_printable_reader`, only the
+    /// top-level header is consulted.
+    #[cfg(feature = "base64")]
+    pub fn base64_body_reader(mut self) -> io::Result<Base64Reader<Self>> {
+        let encoded = unfold_headers(self.head()?).iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case(b"Content-Transfer-Encoding")
+                && value.trim_ascii().eq_ignore_ascii_case(b"base64")
+        });
+        self.skip_head()?;
+        Ok(Base64Reader::new(self, encoded))
+    }
+
+    /// Returns the `charset` parameter of the message's `Content-Type` header, lowercased, or
+    /// `None` if either is missing. This should be called before reading from the entry.
+    pub fn charset(&mut self) -> io::Result<Option<String>> {
+        let charset = unfold_headers(self.head()?)
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(b"Content-Type"))
+            .and_then(|(_, value)| mime::parameter(&value, b"charset"));
+        Ok(charset.map(|charset| String::from_utf8_lossy(&charset).to_lowercase()))
+    }
+
+    /// Writes the message's attachments to new files in `dir`, returning their paths in the order
+    /// the attachments appear. A part is an attachment if its `Content-Disposition` is
+    /// `attachment` or it has a file name (the `filename` parameter of `Content-Disposition` or the
+    /// `name` parameter of `Content-Type`), and multipart messages are searched all the way down.
+    /// Base64 and quoted-printable parts are decoded.
+    ///
+    /// Files are named after the attachments, with any directories removed from the names, or
+    /// `attachment` if they have none, and numbered rather than overwriting an existing file. The
+    /// whole message is read into memory. This should be called before reading from the entry.
+    pub fn extract_attachments(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let headers = unfold_headers(self.head()?);
+        self.skip_head()?;
+        let mut body = Vec::new();
+        self.read_to_end(&mut body)?;
+        mime::extract_attachments(&headers, &body, dir)
+    }
+
+    /// Parses the envelope (`From `) line. Returns `None` if the message doesn't start with one,
+    /// which can only happen for the first message. This should be called before reading from the
+    /// entry.
+    pub fn from_line(&mut self) -> io::Result<Option<FromLine>> {
+        Ok(FromLine::parse(self.envelope()?))
+    }
+
+    /// Parses the envelope line and returns it along with the entry, moved past it so reading
+    /// starts at the first header. Returns an `InvalidData` error if the message doesn't start with
+    /// a `From ` line, which can only happen for the first message. This should be called before
+    /// reading from the entry.
+    pub fn into_parts(mut self) -> io::Result<(FromLine, Self)> {
+        let envelope = self.envelope()?;
+        let len = envelope.len();
+        let from = FromLine::parse(envelope).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "message has no envelope line")
+        })?;
+        self.head.pos = len;
+        Ok((from, self))
+    }
+
+    /// Returns the length of the envelope line, including its line ending, so exactly that many
+    /// bytes can be skipped to reach the headers. A message which is only an envelope line may
+    /// have no line ending. Returns 0 if the message doesn't start with `From `, which can only
+    /// happen for the first message. This should be called before reading from the entry.
+    pub fn from_line_len(&mut self) -> io::Result<usize> {
+        let envelope = self.envelope()?;
+        Ok(if envelope.starts_with(b"From ") {
+            envelope.len()
+        } else {
+            0
+        })
+    }
+
+    /// Returns the line ending before this message's `From ` line, or `LeadingSeparator::None` for
+    /// the first message, so a writer can reproduce the file exactly.
+    pub fn leading_separator(&self) -> LeadingSeparator {
+        self.inner.leading_separator
+    }
+
+    /// Returns an identifier for this message which stays the same each time the file is read, for
+    /// use as a key in an external index: its offset in the stream and its `Message-ID`, as
+    /// `<offset>:<message-id>`. Message IDs aren't reliably unique, hence the offset. A message
+    /// without a `Message-ID` gets a 64-bit FNV-1a hash of its envelope and headers instead, as
+    /// `<offset>#<hash in hex>`. This should be called before reading from the entry.
+    pub fn stable_id(&mut self) -> io::Result<String> {
+        self.head()?;
+        let offset = self.inner.position() - self.head.stream_unread() as u64;
+        let head = &self.head.bytes;
+        let info = ThreadingInfo::from_headers(&unfold_headers(head));
+        Ok(match info.message_id.filter(|id| !id.is_empty()) {
+            Some(id) => format!("{}:{}", offset, String::from_utf8_lossy(&id)),
+            None => format!("{}#{:016x}", offset, fnv1a(head)),
+        })
+    }
+
+    /// Returns the read/answered/flagged/deleted/draft state recorded in the `Status` and
+    /// `X-Status` headers. This should be called before reading from the entry.
+    pub fn flags(&mut self) -> io::Result<MessageFlags> {
+        Ok(MessageFlags::from_headers(&unfold_headers(self.head()?)))
+    }
+
+    /// Reads the rest of the message in pieces of `chunk_size` bytes (the last may be shorter),
+    /// passing each to `f`. Stops early if `f` returns `Break`; the rest of the message is then
+    /// skipped by the next call to `MboxReader::next` as usual.
+    pub fn read_chunks(
+        &mut self,
+        chunk_size: usize,
+        mut f: impl FnMut(&[u8]) -> io::Result<ControlFlow<()>>,
+    ) -> io::Result<()> {
+        assert!(chunk_size > 0);
+        let mut chunk = vec![0; chunk_size];
+        loop {
+            let mut filled = 0;
+            while filled < chunk_size {
+                match self.read(&mut chunk[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled == 0 {
+                return Ok(());
+            }
+            if f(&chunk[..filled])?.is_break() || filled < chunk_size {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Copies the rest of the message to `w` straight from the reader's buffer, without holding
+    /// the whole message in memory, and returns the number of bytes copied. Like `io::copy`, but
+    /// stopping at the end of the message.
+    pub fn copy_to(&mut self, w: &mut impl Write) -> io::Result<u64> {
+        let mut copied = 0;
+        loop {
+            let available = self.fill_buf()?;
+            if available.is_empty() {
+                return Ok(copied);
+            }
+            w.write_all(available)?;
+            let len = available.len();
+            self.consume(len);
+            copied += len as u64;
+        }
+    }
+
+    /// Returns an iterator over the rest of the message's lines, each with the offset where it
+    /// starts, counting from the start of the message (or from wherever reading had got to, if it
+    /// had already begun). Line endings are removed, as by [`BufRead::lines`], but counted in the
+    /// offsets. The last line may have no line ending. The iterator ends after an error.
+    pub fn lines_with_offsets(
+        &mut self,
+    ) -> impl Iterator<Item = io::Result<(u64, Vec<u8>)>> + use<'_, 'a, R> {
+        let mut offset = 0;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let mut line = Vec::new();
+            match self.read_until(b'\n', &mut line) {
+                Ok(0) => {
+                    done = true;
+                    None
+                }
+                Ok(len) => {
+                    let start = offset;
+                    offset += len as u64;
+                    let content_len = headers::trim_line_ending(&line).len();
+                    line.truncate(content_len);
+                    Some(Ok((start, line)))
+                }
+                Err(e) => {
+                    done = true;
+                    Some(Err(e))
+                }
+            }
+        })
+    }
+
+    /// Returns an iterator over the message's lines, like [`lines_with_offsets`], each with its
+    /// quote depth: the number of `>` before `From ` in the line as it is in the stream, for lines
+    /// which were unescaped by removing one of them, and otherwise 0. So with
+    /// [`Dialect::Mboxrd`], `>>From ` is returned as `>From ` with depth 2. This should be called
+    /// before reading from the entry, since the first line is taken to be the envelope line.
+    ///
+    /// [`lines_with_offsets`]: MboxEntry::lines_with_offsets
+    pub fn lines_with_quote_depth(
+        &mut self,
+    ) -> impl Iterator<Item = io::Result<(usize, Vec<u8>)>> + use<'_, 'a, R> {
+        let dialect = match self.inner.single_message {
+            true => Dialect::Raw,
+            false => self.inner.dialect,
+        };
+        self.lines_with_offsets().enumerate().map(move |(i, line)| {
+            let (_, line) = line?;
+            let quotes = line.iter().take_while(|&&b| b == b'>').count();
+            // every line the dialect escapes has been unescaped, so which they were can be told
+            // from what is left
+            let unescaped = i > 0
+                && line[quotes..].starts_with(b"From ")
+                && match dialect {
+                    Dialect::Mboxrd => true,
+                    Dialect::Mboxo => quotes == 0,
+                    Dialect::Raw => false,
+                };
+            Ok((if unescaped { quotes + 1 } else { 0 }, line))
+        })
+    }
+
+    /// Returns whether reading has stopped at the start of the next message, as opposed to the end
+    /// of the stream. Only meaningful once a read has returned `Ok(0)`.
+    pub fn at_boundary(&self) -> bool {
+        self.head.unread() == 0 && self.inner.eom()
+    }
+
+    /// Returns up to `k` bytes from the current position without consuming them, e.g. to sniff the
+    /// type of a body. Fewer are only returned if the message ends first. Reading any more than the
+    /// reader's buffer holds copies them aside, so this is meant for small `k`.
+    pub fn peek(&mut self, k: usize) -> io::Result<&[u8]> {
+        let head = &mut *self.head;
+        let unread_head = head.bytes.len() - head.pos;
+        if unread_head == 0 && head.ahead.is_empty() {
+            // it may all be in the buffer already
+            let available = self.inner.fill_buf()?.len();
+            if available >= k || available == 0 || self.inner.next_message_start.is_some() {
+                let start = self.inner.ready_start;
+                return Ok(&self.inner.buffer[start..start + available.min(k)]);
+            }
+        }
+        while unread_head + head.ahead.len() < k {
+            let before = self.inner.position();
+            let available = self.inner.fill_buf()?;
+            if available.is_empty() {
+                head.dropped += (self.inner.position() - before) as usize;
+                break;
+            }
+            let len = available.len().min(k - unread_head - head.ahead.len());
+            head.ahead.extend_from_slice(&available[..len]);
+            head.add_escapes(len, self.inner.escapes_in_next(len));
+            self.inner.consume(len);
+        }
+        if head.ahead.is_empty() {
+            let len = unread_head.min(k);
+            return Ok(&head.bytes[head.pos..head.pos + len]);
+        }
+        if unread_head == 0 {
+            return Ok(&head.ahead[..head.ahead.len().min(k)]);
+        }
+        head.peeked.clear();
+        head.peeked.extend_from_slice(&head.bytes[head.pos..]);
+        head.peeked.extend_from_slice(&head.ahead);
+        head.peeked.truncate(k);
+        Ok(&head.peeked)
+    }
+
+    /// Reads like `read`, but when a non-blocking underlying reader has nothing ready, waits for
+    /// it and tries again, returning a `TimedOut` error if nothing has arrived by `deadline`. It
+    /// checks back every millisecond, so may return up to that long after `deadline`. Nothing is
+    /// lost on timing out, and reading can carry on. A blocking reader which stalls can't be
+    /// interrupted like this: give it a read timeout of its own instead, e.g. with
+    /// `TcpStream::set_read_timeout`.
+    pub fn read_with_deadline(&mut self, buf: &mut [u8], deadline: Instant) -> io::Result<usize> {
+        loop {
+            match self.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "message not read by the deadline",
+                ));
+            }
+            std::thread::sleep(DEADLINE_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Reads like `read`, and also writes whatever was read to `tee`, e.g. to archive the message
+    /// as it is parsed. The bytes are written as `read` returns them, unescaped.
+    pub fn read_teeing(&mut self, buf: &mut [u8], tee: &mut impl Write) -> io::Result<usize> {
+        let n = self.read(buf)?;
+        tee.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    /// Reads the rest of the message into memory, so it can outlive the `MboxReader` (e.g. to be
+    /// sent to another thread).
+    pub fn into_owned_reader(mut self) -> io::Result<io::Cursor<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        self.read_to_end(&mut bytes)?;
+        Ok(io::Cursor::new(bytes))
+    }
+
+    /// Reads the rest of the message into shared storage, which can be cloned cheaply and handed
+    /// to several consumers at once.
+    pub fn into_shared(mut self) -> io::Result<Arc<[u8]>> {
+        let mut bytes = Vec::new();
+        self.read_to_end(&mut bytes)?;
+        Ok(bytes.into())
+    }
+}
+
+impl<'a, R: Read> Read for MboxEntry<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let copied = available.len().min(buf.len());
+        buf[..copied].copy_from_slice(&available[..copied]);
+        self.consume(copied);
+        Ok(copied)
+    }
+}
+
+impl<'a, R: Read> BufRead for MboxEntry<'a, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.head.pos < self.head.bytes.len() {
+            return Ok(&self.head.bytes[self.head.pos..]);
+        }
+        if !self.head.ahead.is_empty() {
+            return Ok(&self.head.ahead);
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.head.pos < self.head.bytes.len() {
+            assert!(amt <= self.head.bytes.len() - self.head.pos);
+            self.head.pos += amt;
+        } else if !self.head.ahead.is_empty() {
+            self.head.consume_ahead(amt);
+        } else {
+            self.inner.consume(amt)
+        }
+    }
+}
+
+const DEFAULT_CAPACITY: usize = 8192;
+const DEFAULT_MAX_CAPACITY: usize = 1 << 20;
+/// Matched from the `\n`, so a `\r` before it stays with the previous message whatever the line
+/// endings elsewhere in the file.
+const MAGIC_WORD: [u8; 6] = [0x0A, 0x46, 0x72, 0x6F, 0x6D, 0x20];
+const UTF8_BOM: [u8; 3] = [0xEF, 0xBB, 0xBF];
+/// How often `MboxEntry::read_with_deadline` checks whether a waiting reader is ready.
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Returns the index just after the first `\n` in `haystack` which is followed by `From `, i.e.
+/// the index where the next message starts. A `From ` at the very start of `haystack` isn't a
+/// boundary, since it isn't preceded by a newline.
+///
+/// This is the scanning primitive used by [`MboxReader`], for callers who do their own buffering.
+/// Note that a boundary split across two calls isn't found: carry the last five bytes of one
+/// buffer over to the next.
+pub fn find_boundary(haystack: &[u8]) -> Option<usize> {
+    memmem::find(haystack, &MAGIC_WORD).map(|i| i + 1)
+}
+
+/// Like [`find_boundary`], but for a haystack held in several pieces, e.g. a chain of network
+/// buffers, which needn't be copied into one. Finds a magic word split between pieces, or spread
+/// over several short ones. Returns the index of the piece where the next message starts and its
+/// index within that piece.
+pub fn find_boundary_segmented(segments: &[&[u8]]) -> Option<(usize, usize)> {
+    // the last bytes of the segments before the current one, if a magic word could start there
+    let mut tail: Vec<u8> = Vec::with_capacity(2 * MAGIC_WORD.len());
+    // the offset of `tail[0]`, counting from the start of the first segment
+    let mut tail_start = 0;
+    let mut offset = 0;
+    for segment in segments {
+        if !tail.is_empty() {
+            let tail_len = tail.len();
+            tail.extend_from_slice(&segment[..segment.len().min(MAGIC_WORD.len() - 1)]);
+            // only one starting in the tail is new; one wholly in the segment is found below
+            if let Some(i) = find_boundary(&tail).filter(|&i| i <= tail_len) {
+                return Some(locate(segments, tail_start + i));
+            }
+            tail.truncate(tail_len);
+        }
+        if let Some(i) = find_boundary(segment) {
+            return Some(locate(segments, offset + i));
+        }
+        tail.extend_from_slice(segment);
+        let keep = tail.len().min(MAGIC_WORD.len() - 1);
+        tail.drain(..tail.len() - keep);
+        offset += segment.len();
+        tail_start = offset - keep;
+    }
+    None
+}
+
+/// Returns the segment holding the byte at `offset` in `segments` taken together, and its index
+/// within that segment.
+fn locate(segments: &[&[u8]], mut offset: usize) -> (usize, usize) {
+    for (i, segment) in segments.iter().enumerate() {
+        if offset < segment.len() {
+            return (i, offset);
+        }
+        offset -= segment.len();
+    }
+    unreachable!("offset past the end of the segments")
+}
+
+/// MessageBoundaryReader reads bytes until it reaches the "magic word": `From` preceded by a
+/// newline (0x0A) and followed by a space (0x20). When it reaches the "magic word", it will stop
+/// reading (i.e. return 0 bytes) and the `eom` function will return true. To read the next message,
+/// call `reset_eom`.
+struct MessageBoundaryReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    /// The buffer doubles, up to this size, each time it fills without containing a boundary.
+    max_capacity: usize,
+    /// The size the buffer started at, which `shrink` returns it to.
+    initial_capacity: usize,
+    boundary_in_buffer: bool,
+    buffer_end: usize,
+    ready_start: usize,
+    ready_end: usize,
+    held_back: usize,
+    /// True while the buffer is being filled, so a fill interrupted by an error carries on where
+    /// it stopped when retried.
+    refilling: bool,
+    next_message_start: Option<usize>,
+    /// When set, the stream is one message ending at EOF: no boundaries are found and nothing is
+    /// unescaped.
+    single_message: bool,
+    /// The magic word. It must start with a newline and contain no other, and everything about
+    /// holding bytes back at the end of the buffer is derived from its length.
+    separator: Vec<u8>,
+    /// When set, the whitespace after `From` in the magic word may be a tab instead of a space.
+    lenient_separator: bool,
+    /// When clear, a blank line just before a boundary isn't returned.
+    keep_separator_line: bool,
+    /// When set, no blank lines at the end of a message are returned.
+    trim_trailing_blank_lines: bool,
+    /// How `From ` lines in message bodies were escaped, so we know how to unescape them.
+    dialect: Dialect,
+    /// The last byte consumed, so we can tell how the stream ended.
+    last_byte: Option<u8>,
+    byte_before_last: Option<u8>,
+    /// The line ending before the current message, since it was consumed with the previous one.
+    leading_separator: LeadingSeparator,
+    /// The offset in the underlying stream of `buffer[0]`.
+    buffer_offset: u64,
+    /// Indices where an escaping `>` has been removed from the buffer, in ascending order. Each is
+    /// the index of the byte which followed the removed one.
+    removed: Vec<usize>,
+    /// How many bytes of blank lines were dropped from the end of the stream, when trimming
+    /// trailing blank lines. They come after everything in the buffer and are never returned, but
+    /// still count in `end_offset`.
+    trimmed_at_eof: usize,
+    on_event: Option<Box<dyn FnMut(ReadEvent) + Send>>,
+    /// The offset in the underlying stream just after the last byte read from it, counted as
+    /// bytes are read, for checking the offsets above in debug builds.
+    #[cfg(debug_assertions)]
+    inner_offset: u64,
+}
+
+impl<R: Read> MessageBoundaryReader<R> {
+    #[cfg(test)]
+    fn new(inner: R) -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY, inner)
+    }
+
+    fn with_capacity(capacity: usize, inner: R) -> Self {
+        assert!(capacity >= MAGIC_WORD.len());
+        MessageBoundaryReader {
+            inner,
+            buffer: vec![0; capacity],
+            max_capacity: DEFAULT_MAX_CAPACITY,
+            initial_capacity: capacity,
+            boundary_in_buffer: false,
+            buffer_end: 0,
+            ready_start: 0,
+            ready_end: 0,
+            held_back: 0,
+            refilling: false,
+            next_message_start: None,
+            single_message: false,
+            separator: MAGIC_WORD.to_vec(),
+            lenient_separator: false,
+            keep_separator_line: true,
+            trim_trailing_blank_lines: false,
+            dialect: Dialect::Mboxrd,
+            last_byte: None,
+            byte_before_last: None,
+            leading_separator: LeadingSeparator::None,
+            buffer_offset: 0,
+            removed: Vec::new(),
+            trimmed_at_eof: 0,
+            on_event: None,
+            #[cfg(debug_assertions)]
+            inner_offset: 0,
+        }
+    }
+
+    /// Returns true if the reader has stopped before the beginning of the next message. Returns
+    /// false in all other cases, including end of file.
+    ///
+    /// Note: You may have to read until 0 bytes are returned before this function returns true.
+    fn eom(&self) -> bool {
+        self.next_message_start == Some(self.ready_start)
+    }
+
+    /// Returns true if there is no more data to be read. Returns `io::Result` because it may have
+    /// to read from the underlying stream.
+    fn eof(&mut self) -> io::Result<bool> {
+        // fill first, since that may move past a dropped separator line onto the boundary
+        Ok(self.fill_buf()?.is_empty() && !self.eom())
+    }
+
+    /// If called when `self.eom() == false`, this triggers undefined behavior
+    fn reset_eom(&mut self) {
+        assert!(self.eom());
+        self.next_message_start = None;
+        self.check_offsets();
+    }
+
+    /// Checks, in debug builds, that the buffer accounts for everything read from the underlying
+    /// stream: what has been consumed plus what is still buffered, escapes included, is exactly
+    /// what has been read.
+    fn check_offsets(&self) {
+        #[cfg(debug_assertions)]
+        {
+            assert!(self.ready_start <= self.ready_end && self.ready_end <= self.held_back);
+            assert!(self.held_back <= self.buffer_end && self.buffer_end <= self.buffer.len());
+            let unconsumed = self.buffer_end - self.ready_start
+                + (self.removed.len() - self.removed.partition_point(|&r| r < self.ready_start))
+                + self.trimmed_at_eof;
+            assert_eq!(self.position() + unconsumed as u64, self.inner_offset);
+        }
+    }
+
+    fn report(&mut self, event: ReadEvent) {
+        if let Some(hook) = &mut self.on_event {
+            hook(event);
+        }
+    }
+
+    /// Consumes a UTF-8 byte order mark at the current position, if there is one.
+    fn skip_bom(&mut self) -> io::Result<()> {
+        if self.fill_buf()?.starts_with(&UTF8_BOM) {
+            self.consume(UTF8_BOM.len());
+        }
+        Ok(())
+    }
+
+    /// Consumes any whitespace at the current position. If that runs up to a boundary, continues
+    /// past it so the next read starts at the following `From `.
+    fn skip_leading_whitespace(&mut self) -> io::Result<()> {
+        loop {
+            let available = self.fill_buf()?;
+            let whitespace = available
+                .iter()
+                .take_while(|b| b.is_ascii_whitespace())
+                .count();
+            let done = whitespace < available.len() || whitespace == 0;
+            self.consume(whitespace);
+            if done {
+                break;
+            }
+        }
+        if self.eom() {
+            self.reset_eom();
+        }
+        Ok(())
+    }
+
+    /// Returns the offset in the underlying stream of the byte at `buffer[idx]`, accounting for
+    /// any escaping `>` which have been removed before it.
+    fn offset_of(&self, idx: usize) -> u64 {
+        let removed = self.removed.partition_point(|&r| r <= idx);
+        self.buffer_offset + (idx + removed) as u64
+    }
+
+    /// Returns where escaping `>` characters were removed from the next `len` bytes to be read, as
+    /// indices into them, like `removed`.
+    fn escapes_in_next(&self, len: usize) -> impl Iterator<Item = usize> + '_ {
+        let start = self.ready_start;
+        let first = self.removed.partition_point(|&r| r < start);
+        self.removed[first..]
+            .iter()
+            .take_while(move |&&r| r < start + len)
+            .map(move |&r| r - start)
+    }
+
+    /// The offset in the underlying stream of the next byte to be read. If an escaping `>` was
+    /// removed just before it, this is the offset of the `>`, so a line's offset is where it starts
+    /// in the stream.
+    fn position(&self) -> u64 {
+        let removed = self.removed.partition_point(|&r| r < self.ready_start);
+        self.buffer_offset + (self.ready_start + removed) as u64
+    }
+
+    /// The offset in the underlying stream where the current message ends, once it has all been
+    /// consumed and no boundary follows it: `position`, plus any blank lines trimmed from the end
+    /// of the stream after it, so at EOF this is the length of the stream.
+    fn end_offset(&self) -> u64 {
+        self.position() + self.trimmed_at_eof as u64
+    }
+
+    /// Moves the unconsumed bytes to the front of the buffer and, if it has grown, shrinks it to
+    /// the initial capacity or just big enough for them.
+    fn shrink(&mut self) {
+        if self.buffer.len() <= self.initial_capacity {
+            return;
+        }
+        let shift = self.ready_start;
+        let removed_before = self.removed.partition_point(|&r| r < shift);
+        self.buffer_offset += (shift + removed_before) as u64;
+        self.removed.drain(..removed_before);
+        for r in &mut self.removed {
+            *r -= shift;
+        }
+        self.buffer.copy_within(shift..self.buffer_end, 0);
+        self.ready_start = 0;
+        self.ready_end -= shift;
+        self.held_back -= shift;
+        self.buffer_end -= shift;
+        self.next_message_start = self.next_message_start.map(|start| start - shift);
+
+        self.buffer.truncate(self.initial_capacity.max(self.buffer_end));
+        self.buffer.shrink_to_fit();
+    }
+
+    /// Returns the length of the blank line which ends just before `buffer[idx]`, or 0 if the line
+    /// which ends there isn't empty. Bytes before the start of the buffer have been consumed, so the
+    /// last two are remembered.
+    fn blank_line_before(&self, idx: usize) -> usize {
+        let byte = |back: usize| match idx.checked_sub(back) {
+            Some(i) => Some(self.buffer[i]),
+            None if back - idx == 1 => self.last_byte,
+            None if back - idx == 2 => self.byte_before_last,
+            None => None,
+        };
+        match (byte(3), byte(2), byte(1)) {
+            (_, Some(b'\n'), Some(b'\n')) => 1,
+            (Some(b'\n'), Some(b'\r'), Some(b'\n')) => 2,
+            _ => 0,
+        }
+    }
+
+    /// Returns the index where the blank lines at the end of `buffer[start..end]` begin, or `end`
+    /// if it doesn't end with one. A blank line has nothing but spaces, tabs and `\r` before its
+    /// newline, or before `end` if it is cut off there.
+    fn trailing_blank_lines(&self, start: usize, end: usize) -> usize {
+        let bytes = &self.buffer[start..end];
+        let line_end =
+            |from: usize| memchr(b'\n', &bytes[from..]).map_or(end, |i| start + from + i + 1);
+        match bytes.iter().rposition(|b| !matches!(b, b' ' | b'\t' | b'\r' | b'\n')) {
+            // the last line with anything on it ends at the next newline
+            Some(last) => line_end(last),
+            // all blank, unless `start` is partway through a line which isn't
+            None if matches!(self.last_byte, None | Some(b'\n')) => start,
+            None => line_end(0),
+        }
+    }
+
+    /// Returns the index of the first message start in `buffer[start..end]`, relative to `start`.
+    /// See [`find_boundary`].
+    fn find_magic_word(&self, start: usize, end: usize) -> Option<usize> {
+        let haystack = &self.buffer[start..end];
+        if !self.lenient_separator {
+            return memmem::find(haystack, &self.separator).map(|i| i + 1);
+        }
+        let (&last, prefix) = self.separator.split_last().unwrap();
+        memmem::find_iter(haystack, prefix)
+            .find(|&i| {
+                matches!(haystack.get(i + prefix.len()), Some(&b) if b == last || b == b'\t')
+            })
+            .map(|i| i + 1)
+    }
+
+    /// Changes the magic word, which must be no longer than the buffer. Only for testing so far.
+    #[cfg(test)]
+    fn set_separator(&mut self, separator: &[u8]) {
+        assert!(separator.len() >= 2 && separator.len() <= self.buffer.len());
+        assert!(separator[0] == b'\n' && memchr(b'\n', &separator[1..]).is_none());
+        self.separator = separator.to_vec();
+    }
+
+    /// Discards everything buffered, for when the underlying reader has been moved to `offset`
+    /// some other way. `preceding` is the up to two bytes before `offset`.
+    fn reset_to(&mut self, offset: u64, preceding: &[u8]) {
+        self.leading_separator = match *preceding {
+            [a, b] => LeadingSeparator::before(Some(a), Some(b)),
+            [b] => LeadingSeparator::before(None, Some(b)),
+            _ => LeadingSeparator::None,
+        };
+        self.buffer_end = 0;
+        self.ready_start = 0;
+        self.ready_end = 0;
+        self.held_back = 0;
+        self.refilling = false;
+        self.next_message_start = None;
+        self.last_byte = None;
+        self.byte_before_last = None;
+        self.buffer_offset = offset;
+        self.removed.clear();
+        self.trimmed_at_eof = 0;
+        #[cfg(debug_assertions)]
+        {
+            self.inner_offset = offset;
+        }
+    }
+
+    /// Skips all remaining bytes in the current message, possibly reaching EOF. After calling this,
+    /// either `self.eof()` or `self.eom()` will be true.
+    fn skip_message(&mut self) -> io::Result<()> {
+        loop {
+            let ready = self.fill_buf()?.len();
+            if ready == 0 {
+                break;
+            }
+            self.consume(ready);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> MessageBoundaryReader<R> {
+    /// Moves the underlying reader to `offset` and discards everything buffered.
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        // read the line ending before the message, ending up at `offset`
+        let before = offset.min(2);
+        self.inner.seek(SeekFrom::Start(offset - before))?;
+        let mut preceding = Vec::with_capacity(2);
+        self.inner.by_ref().take(before).read_to_end(&mut preceding)?;
+        self.reset_to(offset, &preceding);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for MessageBoundaryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let copied = available.len().min(buf.len());
+        buf[..copied].copy_from_slice(&available[..copied]);
+        self.consume(copied);
+        Ok(copied)
+    }
+}
+
+impl<R: Read> BufRead for MessageBoundaryReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.ready_start != self.ready_end {
+            // don't refill until all bytes have been consumed
+            return Ok(&self.buffer[self.ready_start..self.ready_end]);
+        }
+
+        if let Some(start) = self.next_message_start {
+            // skip the separator line, if it was left out of the message
+            self.ready_start = start;
+            self.ready_end = start;
+            return Ok(&[]);
+        }
+
+        if self.ready_end == self.held_back && !self.refilling {
+            // we read everything in the buffer and it's time to restart at the beginning, possibly
+            // copying held back bytes
+            // (each escape removed from the buffer left a byte free at the end)
+            if self.buffer_end + self.removed.len() == self.buffer.len()
+                && !self.boundary_in_buffer
+                && self.buffer.len() < self.max_capacity
+            {
+                // a whole buffer went by without a boundary, so this is a big message and we
+                // should read more of it at a time
+                let grown = (self.buffer.len() * 2).min(self.max_capacity);
+                self.buffer.resize(grown, 0);
+            }
+            self.boundary_in_buffer = false;
+
+            let num_held_back = self.buffer_end - self.held_back;
+            self.buffer_offset = self.offset_of(self.held_back);
+            self.removed.clear();
+            if num_held_back > 0 {
+                self.buffer.copy_within(self.held_back..self.buffer_end, 0);
+            }
+
+            self.ready_start = 0;
+            self.ready_end = 0; // the bytes aren't ready until we've checked them for the magic word
+            self.buffer_end = num_held_back;
+            self.held_back = num_held_back; // because it's equal to buffer_end, 0 bytes are held back
+            self.refilling = true;
+        }
+
+        if self.refilling {
+            let mut source_eof = false;
+            let refill_start = self.buffer_end;
+            while self.buffer_end < self.buffer.len() {
+                let bytes_read = self.inner.read(&mut self.buffer[self.buffer_end..])?;
+                self.buffer_end += bytes_read;
+                #[cfg(debug_assertions)]
+                {
+                    self.inner_offset += bytes_read as u64;
+                }
+                self.held_back = self.buffer_end;
+                if bytes_read == 0 {
+                    source_eof = true;
+                    break;
+                }
+            }
+            self.refilling = false;
+            let read_from = self.buffer_offset + refill_start as u64;
+            self.report(ReadEvent::Refill {
+                offset: read_from,
+                len: self.buffer_end - refill_start,
+            });
+            if source_eof {
+                let end = self.buffer_end + self.trimmed_at_eof;
+                self.report(ReadEvent::Eof {
+                    offset: self.buffer_offset + end as u64,
+                });
+            }
+
+            if source_eof && self.trim_trailing_blank_lines {
+                // nothing follows the last message to show where it ends, so drop its blank lines
+                // from the buffer altogether
+                let trimmed_end = self.trailing_blank_lines(0, self.buffer_end);
+                self.trimmed_at_eof += self.buffer_end - trimmed_end;
+                self.buffer_end = trimmed_end;
+                self.held_back = self.buffer_end;
+            }
+
+            // a magic word can have all but its last byte at the end of the buffer
+            let tail_len = self.separator.len() - 1;
+            if self.buffer_end > tail_len {
+                // as long as the buffer holds more than a partial magic word, we want to check for
+                // newlines in the tail which could start one and hold those back
+                // if it's shorter, then we're at source EOF and don't need to
+                let tail = &self.buffer[self.buffer_end - tail_len..self.buffer_end];
+                if let Some(newline_idx) = memchr(b'\n', tail) {
+                    self.held_back = self.buffer_end - tail_len + newline_idx;
+                    // a CRLF blank line before the boundary has to be visible along with it, to
+                    // be dropped
+                    if !self.keep_separator_line
+                        && self.held_back > 2
+                        && self.buffer[self.held_back - 2..self.held_back] == *b"\n\r"
+                    {
+                        self.held_back -= 2;
+                    }
+                }
+            }
+
+            if !source_eof && self.dialect != Dialect::Raw {
+                // an escaped line which runs off the end of the buffer can only be unescaped once we
+                // can see whether it continues with `From `, so hold back its newline too (unless
+                // that would leave nothing ready)
+                if let Some(newline_idx) = memrchr(b'\n', &self.buffer[..self.buffer_end]) {
+                    let tail = &self.buffer[newline_idx + 1..self.buffer_end];
+                    let quotes = tail.iter().take_while(|&&b| b == b'>').count();
+                    if quotes > 0 && newline_idx > 0 && b"From ".starts_with(&tail[quotes..]) {
+                        self.held_back = self.held_back.min(newline_idx);
+                    }
+                }
+            }
+
+            if !source_eof && self.trim_trailing_blank_lines {
+                // blank lines at the end of the buffer may turn out to end the message, so hold
+                // them back until we can see what follows (unless that would leave nothing ready,
+                // in which case they are returned and only later ones are trimmed)
+                let blank_start = self.trailing_blank_lines(0, self.held_back);
+                if blank_start > 0 {
+                    self.held_back = blank_start;
+                }
+            }
+
+            if self.held_back < self.buffer_end {
+                self.report(ReadEvent::HeldBack {
+                    offset: self.buffer_offset + self.held_back as u64,
+                    len: self.buffer_end - self.held_back,
+                });
+            }
+        } else {
+            // we called reset_eom() and are continuing to read pre-buffered content
+            // we don't want to reset any offsets - they are all still accurate
+        }
+
+        // assumptions:
+        // this has to be true, because it's already been checked
+        assert_eq!(self.ready_start, self.ready_end);
+
+        if self.single_message {
+            self.ready_end = self.held_back;
+            self.check_offsets();
+            return Ok(&self.buffer[self.ready_start..self.ready_end]);
+        }
+
+        // held_back is either buffer_end or the index of a newline, and a magic word contains no
+        // newline after its first byte, so none can straddle held_back: one which starts before it
+        // lies wholly inside the range searched, and one which starts at it is searched after the
+        // next refill copies it to the front of the buffer
+        if let Some(start_idx) = self.find_magic_word(self.ready_start, self.held_back) {
+            // the index returned is relative to the start of the slice
+            let absolute_idx = self.ready_start + start_idx;
+            let offset = self.offset_of(absolute_idx);
+            self.report(ReadEvent::Boundary { offset });
+            self.ready_end = absolute_idx;
+            if !self.keep_separator_line {
+                let blank_line = self.blank_line_before(absolute_idx);
+                self.ready_end = (absolute_idx - blank_line).max(self.ready_start);
+            }
+            if self.trim_trailing_blank_lines {
+                self.ready_end = self.trailing_blank_lines(self.ready_start, self.ready_end);
+            }
+            self.next_message_start = Some(absolute_idx);
+            self.boundary_in_buffer = true;
+        } else {
+            self.ready_end = self.held_back;
+        }
+
+        // search for `^>+From `, noting the first `>` of each to be erased
+        let mut escapes = Vec::new();
+        let mut search_start = self.ready_start;
+        if self.dialect != Dialect::Raw {
+            let ready_end = self.ready_end;
+            while let Some(off) = memmem::find(&self.buffer[search_start..ready_end], b"\n>") {
+                let first_lt_idx = search_start + off + 1;
+                search_start = first_lt_idx;
+                let line = &self.buffer[first_lt_idx..ready_end];
+                let quotes = line.iter().take_while(|&&b| b == b'>').count();
+                if !line[quotes..].starts_with(b"From ") {
+                    continue;
+                }
+                if self.dialect == Dialect::Mboxo && quotes != 1 {
+                    continue;
+                }
+                escapes.push(first_lt_idx);
+            }
+        }
+
+        // then erase them all at once, moving each run of bytes between them back over the gap,
+        // so the buffer is only copied through once however many there are
+        if let Some(&first) = escapes.first() {
+            let mut write = first;
+            for (n, &lt_idx) in escapes.iter().enumerate() {
+                let run_end = escapes.get(n + 1).copied().unwrap_or(self.buffer_end);
+                self.buffer.copy_within(lt_idx + 1..run_end, write);
+                self.removed.push(write);
+                write += run_end - lt_idx - 1;
+            }
+            let erased = escapes.len();
+            self.ready_end -= erased;
+            self.held_back -= erased;
+            self.buffer_end -= erased;
+            self.next_message_start = self.next_message_start.map(|x| x - erased);
+        }
+
+        self.check_offsets();
+        Ok(&self.buffer[self.ready_start..self.ready_end])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        assert!(amt <= (self.ready_end - self.ready_start));
+        if amt > 1 {
+            self.byte_before_last = Some(self.buffer[self.ready_start + amt - 2]);
+        } else if amt == 1 {
+            self.byte_before_last = self.last_byte;
+        }
+        if amt > 0 {
+            self.last_byte = Some(self.buffer[self.ready_start + amt - 1]);
+        }
+        self.ready_start += amt;
+        self.check_offsets();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dequoting() {
+        let input_lines: Vec<&[u8]> = vec![
+            b"From first@email.com",
+            b">From some other dude",
+            b"Here's a line of text",
+            b"From second@email.com",
+            b"Another line of text",
+            b">From yet another email address",
+            b"With more lines of text",
+        ];
+        let input: Vec<u8> = input_lines.join(&b"\r\n"[..]);
+        let mut reader = MboxReader::new(input.as_slice());
+
+        let first_message: io::Result<Vec<String>> = reader.next().unwrap().unwrap().lines().collect();
+        let first_message = first_message.unwrap();
+        assert_eq!(first_message.len(), 3);
+        assert_eq!(first_message[0].as_bytes(), input_lines[0]);
+        assert_eq!(first_message[1].as_bytes(), &input_lines[1][1..]);
+        assert_eq!(first_message[2].as_bytes(), input_lines[2]);
+
+        let second_message: io::Result<Vec<String>> = reader.next().unwrap().unwrap().lines().collect();
+        let second_message = second_message.unwrap();
+        assert_eq!(second_message.len(), 4);
+        assert_eq!(second_message[0].as_bytes(), input_lines[3]);
+        assert_eq!(second_message[1].as_bytes(), input_lines[4]);
+        assert_eq!(second_message[2].as_bytes(), &input_lines[5][1..]);
+        assert_eq!(second_message[3].as_bytes(), input_lines[6]);
+        assert!(reader.next().unwrap().is_none());
+    }
+
+    #[test]
+    fn really_deep_quotes() {
+        let mut input: Vec<u8> = vec![0; DEFAULT_CAPACITY - 5];
+        input.extend_from_slice(b"\n>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>From Alice");
+        let reader = MessageBoundaryReader::new(input.as_slice());
+        assert_eq!(reader.lines().last().unwrap().unwrap(), ">>>>>>>>>>>>>>>>>>>>>>>>>>>>>>From Alice");
+    }
+
+    #[test]
+    fn one_byte_reads() {
+        // read one byte at a time and check it behaves properly
+        let input = b"From line1\nFrom line2";
+        let mut reader = MessageBoundaryReader::new(input.as_slice());
+        let mut buf: [u8; 1] = [0];
+        let mut full: Vec<u8> = Vec::with_capacity(input.len());
+        for _ in 0..11 {
+            assert_eq!(1, reader.read(&mut buf).unwrap());
+            full.push(buf[0]);
+        }
+        assert_eq!(0, reader.read(&mut buf).unwrap());
         assert_eq!(&full, &input[..11]);
         assert!(reader.eom());
         reader.reset_eom();
-        for _ in 0..10 {
-            assert_eq!(1, reader.read(&mut buf).unwrap());
-            full.push(buf[0]);
+        for _ in 0..10 {
+            assert_eq!(1, reader.read(&mut buf).unwrap());
+            full.push(buf[0]);
+        }
+        assert_eq!(0, reader.read(&mut buf).unwrap());
+        assert_eq!(&full, &input);
+        assert!(reader.eof().unwrap());
+        assert!(!reader.eom());
+    }
+
+    /// Reads every remaining message in full.
+    fn read_all<R: Read>(mut reader: MboxReader<R>) -> io::Result<Vec<Vec<u8>>> {
+        let mut messages = Vec::new();
+        while let Some(mut item) = reader.next()? {
+            let mut msg = Vec::new();
+            item.read_to_end(&mut msg)?;
+            messages.push(msg);
+        }
+        Ok(messages)
+    }
+
+    /// xorshift64, so the randomized tests are reproducible without any dependencies
+    struct TestRng(u64);
+
+    impl TestRng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Splits `input` the slow and obvious way: a new message starts after every `\n` which is
+    /// followed by `From `.
+    fn reference_split(input: &[u8]) -> Vec<&[u8]> {
+        reference_split_on(input, &MAGIC_WORD)
+    }
+
+    /// Like `reference_split`, but for any magic word `separator`.
+    fn reference_split_on<'a>(input: &'a [u8], separator: &[u8]) -> Vec<&'a [u8]> {
+        let mut messages = Vec::new();
+        let mut start = 0;
+        for (i, window) in input.windows(separator.len()).enumerate() {
+            if window == separator {
+                messages.push(&input[start..i + 1]);
+                start = i + 1;
+            }
+        }
+        if !input.is_empty() {
+            messages.push(&input[start..]);
+        }
+        messages
+    }
+
+    #[test]
+    fn one_byte_reads_randomized() -> io::Result<()> {
+        let pieces: [&[u8]; 7] = [b"a", b"\n", b"\r\n", b"From ", b"\nFrom ", b"\nFrom", b"Fro"];
+        let mut rng = TestRng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..100 {
+            let target_len = rng.below(3 * DEFAULT_CAPACITY);
+            let mut input: Vec<u8> = Vec::with_capacity(target_len + 8);
+            while input.len() < target_len {
+                let piece = pieces[rng.below(pieces.len())];
+                // runs of filler so boundaries land at arbitrary distances from each other
+                let repeat = if piece == b"a" { rng.below(600) } else { 1 };
+                for _ in 0..repeat {
+                    input.extend_from_slice(piece);
+                }
+            }
+
+            let mut reader = MboxReader::new(input.as_slice()).trim_leading(false);
+            let mut messages: Vec<Vec<u8>> = Vec::new();
+            let mut buf: [u8; 1] = [0];
+            while let Some(mut item) = reader.next()? {
+                let mut msg = Vec::new();
+                while item.read(&mut buf)? == 1 {
+                    msg.push(buf[0]);
+                }
+                messages.push(msg);
+            }
+            assert_eq!(messages, reference_split(&input));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn crlf() {
+        // use CRLF line endings and check it works right
+        let input = b"From line1\r\nFrom line2\r\n";
+        let mut reader = MessageBoundaryReader::new(input.as_slice());
+        let mut line = String::new();
+        assert_eq!(reader.read_to_string(&mut line).unwrap(), 12);
+        assert!(reader.eom());
+        assert_eq!(line, "From line1\r\n");
+        reader.reset_eom();
+        line.clear();
+        assert_eq!(reader.read_to_string(&mut line).unwrap(), 12);
+        assert!(reader.eof().unwrap());
+        assert_eq!(line, "From line2\r\n");
+    }
+
+    #[test]
+    fn exact_read_sizes() {
+        // read the exact number of bytes in the first line, then make sure it returns 0 bytes, then the next line
+        let input = b"From line1\nFrom line2";
+        let mut reader = MessageBoundaryReader::new(input.as_slice());
+        let mut buf: [u8; 11] = [0; 11];
+        let bytes_read = reader.read(&mut buf).unwrap();
+        assert_eq!(bytes_read, 11);
+        assert!(reader.eom());
+        assert_eq!(buf[..], input[..11]);
+        let bytes_read = reader.read(&mut buf).unwrap();
+        assert_eq!(bytes_read, 0);
+        reader.reset_eom();
+        assert!(!reader.eom());
+        let bytes_read = reader.read(&mut buf).unwrap();
+        assert_eq!(bytes_read, 10);
+        assert_eq!(buf[..bytes_read], input[11..]);
+        let bytes_read = reader.read(&mut buf).unwrap();
+        assert_eq!(bytes_read, 0);
+        assert!(reader.eof().unwrap());
+        assert!(!reader.eom());
+    }
+
+    /// Returns at most `max` bytes per read.
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        max: usize,
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.max);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn read_ending_inside_magic_word() -> io::Result<()> {
+        let first = b"From a\nbody\n";
+        let input = [&first[..], b"From b\nrest\n"].concat();
+        // the magic word starts at the newline which ends the first message
+        let magic_start = first.len() - 1;
+        for within in 0..=MAGIC_WORD.len() {
+            let size = magic_start + within;
+            // either the caller's reads or the underlying reader's end inside the magic word
+            let sizes = [(size, input.len()), (input.len(), size), (size, size)];
+            for (read_size, chunk_size) in sizes {
+                let inner = ChunkedReader {
+                    data: &input,
+                    max: chunk_size,
+                };
+                let mut reader = MessageBoundaryReader::with_capacity(MAGIC_WORD.len(), inner);
+                let mut buf = vec![0; read_size];
+                let mut message = Vec::new();
+                loop {
+                    let read = reader.read(&mut buf)?;
+                    if read == 0 {
+                        break;
+                    }
+                    message.extend_from_slice(&buf[..read]);
+                }
+                let context = format!("{} bytes in, reads of {}/{}", within, read_size, chunk_size);
+                assert_eq!(message, first, "{}", context);
+                assert!(reader.eom(), "{}", context);
+                reader.reset_eom();
+                message.clear();
+                reader.read_to_end(&mut message)?;
+                assert_eq!(message, b"From b\nrest\n", "{}", context);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn page_boundary() {
+        // let's try a bunch of read sizes with the magic word just off the page boundary
+        for offset in 0..MAGIC_WORD.len() + 1 {
+            let mut input: Vec<u8> = vec![0; DEFAULT_CAPACITY];
+            for _ in 0..offset {
+                input.pop();
+            }
+            input.extend_from_slice(&MAGIC_WORD);
+            input.extend_from_slice(b"test123");
+            let input = input;
+
+            for block_size in 0..32 {
+                let mut buffer: Vec<u8> = vec![0; 128 + block_size];
+                let mut result: Vec<u8> = Vec::with_capacity(DEFAULT_CAPACITY + 16);
+                let mut reader = MessageBoundaryReader::new(input.as_slice());
+                while !reader.eom() {
+                    let read = reader.read(&mut buffer).unwrap();
+                    assert!(read > 0);
+                    // if read == 0 {
+                    //     let eom = reader.eom();
+                    //     let eof = reader.eof().unwrap();
+                    //     println!("{}, {}", eom, eof);
+                    // }
+                    result.extend_from_slice(&buffer[..read]);
+                }
+                // 1 byte for the newline, plus the total number of null bytes
+                assert_eq!(result.len(), 1 + DEFAULT_CAPACITY - offset);
+                assert_eq!(*result.last().unwrap(), b'\n');
+                let read = reader.read(&mut buffer).unwrap();
+                assert_eq!(read, 0);
+                reader.reset_eom();
+
+                let mut v: Vec<u8> = Vec::new();
+                reader.read_to_end(&mut v).unwrap();
+                assert!(reader.eof().unwrap());
+                assert_eq!(b"From test123", &v[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn mbox_reader() -> io::Result<()> {
+        let raw_messages = [
+            b"From test1\ntest1\n",
+            b"From test2\ntest2\n",
+            b"From test3\ntest3\n",
+        ];
+        let input: Vec<u8> = raw_messages
+            .iter()
+            .flat_map(|&x| x.iter().copied())
+            .collect();
+        let mut reader = MboxReader::new(input.as_slice());
+        let mut messages: Vec<String> = Vec::new();
+        while let Some(mut item) = reader.next()? {
+            let mut msg = String::new();
+            assert_eq!(item.read_to_string(&mut msg)?, 17);
+            messages.push(msg);
+        }
+        let message_bytes: Vec<&[u8]> = messages.iter().map(|x| x.as_bytes()).collect();
+        assert_eq!(&raw_messages[..], message_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn magic_word_across_held_back() -> io::Result<()> {
+        // slide boundaries, other newlines and escape-like lines across the end of the first and
+        // second buffer fills, so the held-back bytes cut through (or land just after) a magic word
+        // both before and after they're copied to the front of the buffer
+        for capacity in [8, 13, 16] {
+            for pad in 0..3 * capacity {
+                for tail in [&b"\n"[..], b"\n\n", b"\r\n", b"\n>Frox\n", b"\nFro\n"] {
+                    let mut input = b"From a\n".to_vec();
+                    input.resize(input.len() + pad, b'a');
+                    input.extend_from_slice(b"\nFrom b");
+                    input.extend_from_slice(tail);
+                    input.extend_from_slice(b"From c\nFrom d");
+                    input.extend_from_slice(tail);
+
+                    let reader = MboxReader::with_capacity(capacity, input.as_slice());
+                    assert_eq!(
+                        read_all(reader)?,
+                        reference_split(&input),
+                        "capacity {}, pad {}, tail {:?}",
+                        capacity,
+                        pad,
+                        tail
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn boundary_at_buffer_end() -> io::Result<()> {
+        // put the newline of the boundary at every position around the end of the first buffer
+        // fill, including the case where it is the only held-back byte and the case where the
+        // magic word ends exactly at buffer_end, and with EOF right after the boundary
+        for newline_idx in DEFAULT_CAPACITY - 8..DEFAULT_CAPACITY + 2 {
+            for tail in [&b""[..], b"b", b"b\n"] {
+                let mut input = b"From a".to_vec();
+                input.resize(newline_idx, b'a');
+                input.extend_from_slice(&MAGIC_WORD);
+                input.extend_from_slice(tail);
+
+                let mut reader = MboxReader::new(input.as_slice());
+                let mut messages: Vec<Vec<u8>> = Vec::new();
+                while let Some(mut item) = reader.next()? {
+                    let mut msg = Vec::new();
+                    item.read_to_end(&mut msg)?;
+                    messages.push(msg);
+                }
+                assert_eq!(messages.len(), 2, "newline at {}", newline_idx);
+                assert_eq!(messages[0], &input[..newline_idx + 1]);
+                assert_eq!(messages[1], &input[newline_idx + 1..]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replace_inner() -> io::Result<()> {
+        let input = b"From test1\ntest1\nFrom test2\ntest2\nFrom test3\ntest3\n";
+        // the first source dries up halfway through the second message
+        let split = 22;
+        let mut reader = MboxReader::new(io::Cursor::new(input[..split].to_vec()));
+        let mut first = String::new();
+        reader.next()?.unwrap().read_to_string(&mut first)?;
+        assert_eq!(first, "From test1\ntest1\n");
+
+        let old = reader.replace_inner(io::Cursor::new(input[split..].to_vec()));
+        assert_eq!(old.position(), split as u64);
+
+        let mut messages: Vec<String> = Vec::new();
+        while let Some(mut item) = reader.next()? {
+            let mut msg = String::new();
+            item.read_to_string(&mut msg)?;
+            messages.push(msg);
+        }
+        assert_eq!(messages, ["From test2\ntest2\n", "From test3\ntest3\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn single_message() -> io::Result<()> {
+        let input = b"Subject: hello\n\nFrom here on, this is the body.\n>From me\n";
+        let mut reader = MboxReader::new(input.as_slice()).single_message(true);
+        let mut msg: Vec<u8> = Vec::new();
+        reader.next()?.unwrap().read_to_end(&mut msg)?;
+        assert_eq!(msg, input);
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn skip_to_matching() -> io::Result<()> {
+        let input = b"From a\nTo: bob@example.com\n\nhello\nFrom b\nSubject: hi\nTo: carol@example.org,\n dave@example.net\n\nhello\nFrom c\nTo: erin@example.net\n\n";
+        let mut reader = MboxReader::new(input.as_slice());
+        assert!(reader.skip_to_matching(b"to", b"dave@example.net")?);
+        let mut msg = String::new();
+        reader.next()?.unwrap().read_to_string(&mut msg)?;
+        assert_eq!(msg, "From b\nSubject: hi\nTo: carol@example.org,\n dave@example.net\n\nhello\n");
+        assert!(reader.skip_to_matching(b"TO", b"erin")?);
+        // already positioned on a match, so this stays put
+        assert!(reader.skip_to_matching(b"to", b"erin")?);
+        msg.clear();
+        reader.next()?.unwrap().read_to_string(&mut msg)?;
+        assert_eq!(msg, "From c\nTo: erin@example.net\n\n");
+        assert!(!reader.skip_to_matching(b"to", b"bob")?);
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn extract_threading_headers() -> io::Result<()> {
+        let input = b"From a\nMessage-ID: <1@example.com>\n\nFirst\nFrom b\nMessage-Id: <2@example.com>\nIn-Reply-To: <1@example.com>\nReferences: <1@example.com>\n\nSecond\nFrom c\nmessage-id: <3@example.com>\nIn-Reply-To: <2@example.com>\nReferences: <1@example.com>\n <2@example.com>\n\nThird\n";
+        let mut reader = MboxReader::new(input.as_slice());
+        let threads = reader.extract_threading_headers()?;
+        let id = |s: &str| s.as_bytes().to_vec();
+        assert_eq!(
+            threads,
+            [
+                ThreadingInfo {
+                    message_id: Some(id("<1@example.com>")),
+                    in_reply_to: None,
+                    references: vec![],
+                },
+                ThreadingInfo {
+                    message_id: Some(id("<2@example.com>")),
+                    in_reply_to: Some(id("<1@example.com>")),
+                    references: vec![id("<1@example.com>")],
+                },
+                ThreadingInfo {
+                    message_id: Some(id("<3@example.com>")),
+                    in_reply_to: Some(id("<2@example.com>")),
+                    references: vec![id("<1@example.com>"), id("<2@example.com>")],
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn into_owned_reader() -> io::Result<()> {
+        let input = b"From test1\ntest1\nFrom test2\ntest2\n";
+        let mut reader = MboxReader::new(input.as_slice());
+        let owned = reader.next()?.unwrap().into_owned_reader()?;
+        let handle = std::thread::spawn(move || {
+            let mut owned = owned;
+            let mut msg = String::new();
+            owned.read_to_string(&mut msg).map(|_| msg)
+        });
+        let mut second = String::new();
+        reader.next()?.unwrap().read_to_string(&mut second)?;
+        assert_eq!(handle.join().unwrap()?, "From test1\ntest1\n");
+        assert_eq!(second, "From test2\ntest2\n");
+        Ok(())
+    }
+
+    #[test]
+    fn into_shared() -> io::Result<()> {
+        let input = b"From test1\ntest1\nFrom test2\ntest2\n";
+        let mut reader = MboxReader::with_capacity(8, input.as_slice());
+        let shared = reader.next()?.unwrap().into_shared()?;
+        let handle = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || shared.to_vec())
+        };
+        assert_eq!(handle.join().unwrap(), &shared[..]);
+        assert_eq!(&shared[..], b"From test1\ntest1\n");
+        assert_eq!(reader.next_message()?.unwrap(), b"From test2\ntest2\n");
+        Ok(())
+    }
+
+    #[test]
+    fn lenient_separator() -> io::Result<()> {
+        let input = b"From a\nfirst\nFrom\tb\nsecond\nFrom  c\nthird\n";
+        assert_eq!(
+            read_all(MboxReader::new(input.as_slice()))?,
+            [&b"From a\nfirst\nFrom\tb\nsecond\n"[..], b"From  c\nthird\n"]
+        );
+
+        let lenient = MboxReader::new(input.as_slice()).lenient_separator(true);
+        assert_eq!(
+            read_all(lenient)?,
+            [&b"From a\nfirst\n"[..], b"From\tb\nsecond\n", b"From  c\nthird\n"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn find_boundary() {
+        assert_eq!(super::find_boundary(b"\nFrom a"), Some(1));
+        assert_eq!(super::find_boundary(b"From a\nbody\nFrom b"), Some(12));
+        assert_eq!(super::find_boundary(b"From a\nbody\nFrom "), Some(12));
+        assert_eq!(super::find_boundary(b"From a\nbody\nFrom"), None);
+        assert_eq!(super::find_boundary(b"From a\nbody\n>From b"), None);
+        assert_eq!(super::find_boundary(b""), None);
+    }
+
+    #[test]
+    fn find_boundary_segmented() {
+        let input = b"From a\nbody\nFrom b\n";
+        // split in two at every point, including through the magic word
+        for split in 0..=input.len() {
+            let (left, right) = input.split_at(split);
+            let expected = if split <= 12 { (1, 12 - split) } else { (0, 12) };
+            assert_eq!(
+                super::find_boundary_segmented(&[left, right]),
+                Some(expected),
+                "split at {}",
+                split
+            );
+        }
+
+        let segments: [&[u8]; 6] = [b"From a\nbody", b"", b"\nF", b"r", b"", b"om b\n"];
+        assert_eq!(super::find_boundary_segmented(&segments), Some((2, 1)));
+        let segments: [&[u8]; 3] = [b"body\nFr", b"om", b"\n"];
+        assert_eq!(super::find_boundary_segmented(&segments), None);
+        let segments: [&[u8]; 3] = [b"\nFr", b"o", b"m \nFrom "];
+        assert_eq!(super::find_boundary_segmented(&segments), Some((0, 1)));
+        assert_eq!(super::find_boundary_segmented(&[]), None);
+    }
+
+    #[test]
+    fn read_chunks() -> io::Result<()> {
+        let input = b"From test1\nsome longer text\nFrom test2\ntest2\n";
+        let mut reader = MboxReader::new(input.as_slice());
+
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        reader.next()?.unwrap().read_chunks(4, |chunk| {
+            chunks.push(chunk.to_vec());
+            Ok(ControlFlow::Break(()))
+        })?;
+        assert_eq!(chunks, [b"From"]);
+
+        chunks.clear();
+        reader.next()?.unwrap().read_chunks(4, |chunk| {
+            chunks.push(chunk.to_vec());
+            Ok(ControlFlow::Continue(()))
+        })?;
+        assert_eq!(chunks.concat(), b"From test2\ntest2\n");
+        assert_eq!(chunks.len(), 5);
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn strip_bom() -> io::Result<()> {
+        let mut input = UTF8_BOM.to_vec();
+        input.extend_from_slice(b"From test1\ntest1\nFrom test2\ntest2\n");
+        let mut reader = MboxReader::new(input.as_slice());
+        let mut first = reader.next()?.unwrap();
+        assert_eq!(first.from_line()?.unwrap().sender, b"test1");
+        assert_eq!(first.into_owned_reader()?.into_inner(), b"From test1\ntest1\n");
+        assert_eq!(reader.next_message()?.unwrap(), b"From test2\ntest2\n");
+
+        let messages = read_all(MboxReader::new(input.as_slice()).strip_bom(false))?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], &input[..20]);
+
+        // only at the start of the stream, and whitespace after it is kept
+        let input = b"\xEF\xBB\xBF\nFrom a\n\xEF\xBB\xBF\n";
+        let messages = read_all(MboxReader::new(input.as_slice()))?;
+        assert_eq!(messages, [&b"\n"[..], b"From a\n\xEF\xBB\xBF\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn trim_leading() -> io::Result<()> {
+        let input = b" \r\n\nFrom test1\ntest1\nFrom test2\ntest2\n";
+        let messages = read_all(MboxReader::new(input.as_slice()))?;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], b" \r\n\n");
+
+        for capacity in [6, 7, 8, 4096] {
+            let reader = MboxReader::with_capacity(capacity, input.as_slice()).trim_leading(true);
+            let messages = read_all(reader)?;
+            assert_eq!(messages, [&b"From test1\ntest1\n"[..], b"From test2\ntest2\n"]);
+        }
+
+        let mut input = UTF8_BOM.to_vec();
+        input.extend_from_slice(b"\n\t\nFrom test1\n");
+        let messages = read_all(MboxReader::new(input.as_slice()).trim_leading(true))?;
+        assert_eq!(messages, [b"From test1\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn flags() -> io::Result<()> {
+        let input = b"From a\nStatus: RO\nX-Status: AF\n\nbody\n";
+        let mut reader = MboxReader::new(input.as_slice());
+        let mut entry = reader.next()?.unwrap();
+        let flags = entry.flags()?;
+        assert!(flags.seen && flags.answered && flags.flagged);
+        assert!(!flags.deleted && !flags.draft);
+        // inspecting the flags doesn't consume anything
+        let mut msg = Vec::new();
+        entry.read_to_end(&mut msg)?;
+        assert_eq!(msg, input);
+        Ok(())
+    }
+
+    #[test]
+    fn last_message_had_trailing_newline() -> io::Result<()> {
+        for (input, expected) in [
+            (&b"From a\nfirst\nFrom b\nsecond\n"[..], Some(true)),
+            (b"From a\nfirst\nFrom b\nsecond", Some(false)),
+            (b"", None),
+        ] {
+            let mut reader = MboxReader::new(input);
+            if !input.is_empty() {
+                reader.next()?;
+                assert_eq!(reader.last_message_had_trailing_newline()?, None);
+            }
+            while reader.next()?.is_some() {}
+            assert_eq!(reader.last_message_had_trailing_newline()?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_message_at() -> io::Result<()> {
+        let input = b"From test1\ntest1\nFrom test2\ntest2\nFrom test3\ntest3\n";
+        let mut reader = MboxReader::new(io::Cursor::new(&input[..]));
+        assert_eq!(reader.read_message_at(17)?, b"From test2\ntest2\n");
+        assert_eq!(reader.next_message()?.unwrap(), b"From test3\ntest3\n");
+        assert_eq!(reader.read_message_at(0)?, b"From test1\ntest1\n");
+        assert_eq!(reader.read_message_at(34)?, b"From test3\ntest3\n");
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn last_message() -> io::Result<()> {
+        let mut input = Vec::new();
+        for i in 0..1000 {
+            input.extend_from_slice(format!("From {}\n\n>From body {}\n\n", i, i).as_bytes());
+        }
+        let mut reader = MboxReader::new(io::Cursor::new(input.as_slice()));
+        assert_eq!(
+            reader.last_message()?.unwrap(),
+            b"From 999\n\nFrom body 999\n\n"
+        );
+        assert!(reader.next()?.is_none());
+
+        // a boundary split between the chunks searched, or either side of them
+        for body_len in 8175..8195 {
+            let mut input = b"From first\n\nFrom last\n".to_vec();
+            input.resize(input.len() + body_len, b'x');
+            let mut reader = MboxReader::new(io::Cursor::new(input.as_slice()));
+            assert_eq!(reader.last_message()?.unwrap(), &input[12..], "body {}", body_len);
+        }
+
+        for input in [&b"From only\nbody\n"[..], b"no envelope\n"] {
+            let mut reader = MboxReader::new(io::Cursor::new(input));
+            assert_eq!(reader.last_message()?.unwrap(), input);
+        }
+        assert_eq!(MboxReader::new(io::Cursor::new(b"")).last_message()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn current_boundary_offset() -> io::Result<()> {
+        let input = b"From a\n>From x\nbody\nFrom b\n>>From y\nsecond\nFrom c\nthird";
+        let mut reader = MboxReader::new(input.as_slice());
+        assert_eq!(
+            reader.current_boundary_offset().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let mut starts = vec![0];
+        let mut boundaries = Vec::new();
+        while let Some(mut entry) = reader.next()? {
+            // read part of the message, so the rest has to be skipped
+            entry.read_exact(&mut [0; 3])?;
+            let boundary = reader.current_boundary_offset()?;
+            boundaries.push(boundary);
+            if boundary < input.len() as u64 {
+                assert_eq!(input[boundary as usize], b'\n');
+                starts.push(boundary + 1);
+            }
+        }
+        assert_eq!(starts, [0, 20, 43]);
+        assert_eq!(boundaries, [19, 42, input.len() as u64]);
+        Ok(())
+    }
+
+    #[test]
+    fn buffer_growth() -> io::Result<()> {
+        let mut input = b"From big\n".to_vec();
+        let mut line = 0;
+        while input.len() < 1 << 20 {
+            input.extend_from_slice(format!("line {}\nFrom", line).as_bytes());
+            line += 1;
+        }
+        input.extend_from_slice(b"\nFrom small\nbody\n");
+
+        let mut reader = MboxReader::with_capacity(64, input.as_slice()).max_buffer_capacity(4096);
+        assert_eq!(reader.buffer_capacity(), 64);
+        let mut messages = Vec::new();
+        while let Some(msg) = reader.next_message()? {
+            messages.push(msg);
+        }
+        assert_eq!(reader.buffer_capacity(), 4096);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages.concat(), input);
+        assert_eq!(messages[1], b"From small\nbody\n");
+
+        // small messages never fill the buffer without a boundary, so it doesn't grow
+        let mut reader = MboxReader::with_capacity(64, &b"From a\na\nFrom b\nb\n"[..]);
+        while reader.next()?.is_some() {}
+        assert_eq!(reader.buffer_capacity(), 64);
+        Ok(())
+    }
+
+    #[test]
+    fn on_event() -> io::Result<()> {
+        let input = b"From a\n\nfirst\n\nFrom b\n\n>From second\n\nFrom c\n\nthird\n";
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = Arc::clone(&events);
+        let mut reader = MboxReader::with_capacity(16, io::Cursor::new(input.as_slice()))
+            .max_buffer_capacity(16)
+            .on_event(move |event| log.lock().unwrap().push(event));
+        let index = reader.build_index()?;
+        let events = events.lock().unwrap();
+
+        let boundaries: Vec<_> = events
+            .iter()
+            .filter_map(|event| match *event {
+                ReadEvent::Boundary { offset } => Some(offset),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(boundaries, index[1..]);
+        // the refills read the whole stream, in order
+        let mut read = 0;
+        for event in events.iter() {
+            if let ReadEvent::Refill { offset, len } = *event {
+                assert_eq!(offset, read);
+                read += len as u64;
+            }
+        }
+        assert_eq!(read, input.len() as u64);
+        assert!(events.iter().any(|event| matches!(event, ReadEvent::HeldBack { .. })));
+        assert_eq!(
+            events.last(),
+            Some(&ReadEvent::Eof {
+                offset: input.len() as u64
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unescape_in_grown_buffer() -> io::Result<()> {
+        let lines = 80_000;
+        let mut input = b"From big\n".to_vec();
+        let mut expected = input.clone();
+        for i in 0..lines {
+            let quotes = ">".repeat(1 + i % 3);
+            input.extend_from_slice(format!("{}From {}\n", quotes, i).as_bytes());
+            expected.extend_from_slice(format!("{}From {}\n", &quotes[1..], i).as_bytes());
+        }
+        input.extend_from_slice(b"From small\n>From body\n");
+        expected.extend_from_slice(b"From small\nFrom body\n");
+
+        // erased one at a time, each escape would move the rest of the grown buffer
+        let mut reader =
+            MboxReader::with_capacity(64, input.as_slice()).max_buffer_capacity(1 << 20);
+        let mut messages = Vec::new();
+        while let Some(message) = reader.next_message()? {
+            messages.push(message);
+        }
+        assert!(reader.buffer_capacity() >= 1 << 19, "the buffer grew");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages.concat(), expected);
+
+        let index = MboxReader::with_capacity(64, input.as_slice()).build_index_detailed()?;
+        assert_eq!(index.get(1).unwrap().offset, (input.len() - 22) as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_all() -> io::Result<()> {
+        let input = b"From alice Mon Jan  1 00:00:00 2001\nSubject: one\n\nfirst body\nFrom bob\nSubject: two\nTo: carol,\n dave\n\nsecond body\n\nFrom carol\nSubject: three\n";
+        let messages = MboxReader::new(&input[..]).parse_all()?;
+        assert_eq!(messages.len(), 3);
+
+        assert_eq!(
+            messages[0].from,
+            FromLine::parse(b"From alice Mon Jan  1 00:00:00 2001")
+        );
+        assert_eq!(messages[0].headers, [(b"Subject".to_vec(), b"one".to_vec())]);
+        assert_eq!(messages[0].body, b"first body\n");
+
+        assert_eq!(messages[1].from.as_ref().unwrap().sender, b"bob");
+        assert_eq!(messages[1].headers[1], (b"To".to_vec(), b"carol, dave".to_vec()));
+        assert_eq!(messages[1].body, b"second body\n\n");
+
+        assert_eq!(messages[2].headers, [(b"Subject".to_vec(), b"three".to_vec())]);
+        assert_eq!(messages[2].body, b"");
+        Ok(())
+    }
+
+    #[test]
+    fn full_buffer_without_boundary() -> io::Result<()> {
+        // every fill is exactly full and ends with newlines which have to be held back, but there
+        // is never a magic word, so each fill must still make progress
+        for pattern in [&b"\n"[..], b"a\n", b"Fro\n", b"abcde\n"] {
+            for capacity in [6, 7, 8, 11, DEFAULT_CAPACITY] {
+                let input: Vec<u8> = pattern.iter().copied().cycle().take(capacity * 10).collect();
+                let mut reader = MessageBoundaryReader::with_capacity(capacity, input.as_slice());
+                reader.max_capacity = capacity;
+                let mut output: Vec<u8> = Vec::with_capacity(input.len());
+                let mut fills = 0;
+                loop {
+                    let available = reader.fill_buf()?;
+                    if available.is_empty() {
+                        break;
+                    }
+                    fills += 1;
+                    assert!(fills <= input.len(), "no progress");
+                    output.extend_from_slice(available);
+                    let len = available.len();
+                    reader.consume(len);
+                }
+                assert!(reader.eof()?);
+                assert_eq!(output, input, "pattern {:?} capacity {}", pattern, capacity);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn position_follows_reads() -> io::Result<()> {
+        let input = b"From a\n\n>From one\n>>From two\nx>From not\n\nFrom b\n>From\n>From three\n";
+        // where each escape removed when reading is in the input
+        let escapes = [8, 18, 54];
+        for capacity in [6, 7, 8, 11, 16, 4096] {
+            for sizes in [&[1][..], &[2, 3], &[5, 1, 8], &[13]] {
+                let mut reader = MessageBoundaryReader::with_capacity(capacity, input.as_slice());
+                let mut returned = 0;
+                for &size in sizes.iter().cycle() {
+                    let available = reader.fill_buf()?.len();
+                    if available == 0 {
+                        if !reader.eom() {
+                            break;
+                        }
+                        reader.reset_eom();
+                        continue;
+                    }
+                    let amt = size.min(available);
+                    reader.consume(amt);
+                    returned += amt;
+                    let unescaped = escapes
+                        .iter()
+                        .enumerate()
+                        .filter(|&(k, &e)| e - k < returned)
+                        .count();
+                    assert_eq!(
+                        reader.position(),
+                        (returned + unescaped) as u64,
+                        "capacity {} sizes {:?}",
+                        capacity,
+                        sizes
+                    );
+                }
+                assert_eq!(reader.position(), input.len() as u64);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn count_in_bodies() -> io::Result<()> {
+        let mut input = b"From a\nSubject: needle\n\n".to_vec();
+        // with a 16 byte buffer, these land at every position relative to a refill
+        for i in 0..20 {
+            input.extend_from_slice(&b"................"[..i % 16]);
+            input.extend_from_slice(b"needle");
+        }
+        input.extend_from_slice(b"\nFrom b\n\nneedleneedle\nneeneedle\n");
+        let mut reader = MboxReader::with_capacity(16, input.as_slice()).max_buffer_capacity(16);
+        assert_eq!(reader.count_in_bodies(b"needle")?, 23);
+
+        let mut reader = MboxReader::new(&b"From a\n\naaaaa\n"[..]);
+        assert_eq!(reader.count_in_bodies(b"aa")?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn index_with_senders() -> io::Result<()> {
+        let input =
+            b"no envelope\n\nFrom alice@example.com Mon Jan  1 00:00:00 2001\n\nhi\n\nFrom b\xffb\n";
+        let mut reader = MboxReader::with_capacity(8, io::Cursor::new(input.as_slice()));
+        assert_eq!(
+            reader.index_with_senders()?,
+            [
+                (0, String::new()),
+                (13, "alice@example.com".to_string()),
+                (66, "b\u{fffd}b".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_message_size() -> io::Result<()> {
+        let mut input = Vec::new();
+        for size in [100, 5000, 20, 1234] {
+            let header = format!("From {}\n\n", size);
+            input.extend_from_slice(header.as_bytes());
+            input.resize(input.len() + size - header.len() - 1, b'x');
+            input.push(b'\n');
+        }
+        let mut reader = MboxReader::with_capacity(64, io::Cursor::new(input.as_slice()));
+        assert_eq!(reader.max_message_size()?, 5000);
+        assert_eq!(reader.next_message()?.unwrap().len(), 100);
+
+        // once the longest has been read, only the rest count
+        reader.next()?.unwrap().read_exact(&mut [0; 10])?;
+        assert_eq!(reader.max_message_size()?, 1234);
+        assert_eq!(reader.next_message()?.unwrap().len(), 20);
+        assert_eq!(reader.max_message_size()?, 1234);
+        reader.next_message()?;
+        assert_eq!(reader.max_message_size()?, 0);
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_remaining() -> io::Result<()> {
+        let mut input = Vec::new();
+        for i in 0..1000 {
+            input.extend_from_slice(format!("From sender\nSubject: {:04}\n\nbody\n", i).as_bytes());
+        }
+        let mut reader = MboxReader::new(io::Cursor::new(input));
+        let estimate = reader.estimate_remaining()?;
+        assert!((990..=1010).contains(&estimate), "{}", estimate);
+
+        for _ in 0..250 {
+            reader.next()?;
+        }
+        let estimate = reader.estimate_remaining()?;
+        assert!((740..=760).contains(&estimate), "{}", estimate);
+        // estimating doesn't disturb reading
+        let msg = reader.next_message()?.unwrap();
+        assert_eq!(msg, b"From sender\nSubject: 0250\n\nbody\n");
+
+        while reader.next()?.is_some() {}
+        assert_eq!(reader.estimate_remaining()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn unescape_depths() -> io::Result<()> {
+        let input = b"From a\n>From one\n>>From two\n>>>From three\n> From not escaped\n>Fromage\nsay From here\n>From again\n";
+        let messages = read_all(MboxReader::new(&input[..]))?;
+        assert_eq!(
+            messages,
+            [&b"From a\nFrom one\n>From two\n>>From three\n> From not escaped\n>Fromage\nsay From here\nFrom again\n"[..]]
+        );
+
+        let messages = read_all(MboxReader::new(&input[..]).dialect(Dialect::Mboxo))?;
+        assert_eq!(
+            messages,
+            [&b"From a\nFrom one\n>>From two\n>>>From three\n> From not escaped\n>Fromage\nsay From here\nFrom again\n"[..]]
+        );
+
+        let messages = read_all(MboxReader::new(&input[..]).dialect(Dialect::Raw))?;
+        assert_eq!(messages, [&input[..]]);
+        Ok(())
+    }
+
+    #[test]
+    fn escaped_line_across_refill() -> io::Result<()> {
+        // the newline is too far from the end of the buffer to be held back for the magic word, but
+        // the escaped line isn't complete until the next read
+        for newline_idx in DEFAULT_CAPACITY - 50..DEFAULT_CAPACITY {
+            let mut input = b"From a\n".to_vec();
+            input.resize(newline_idx, b'a');
+            input.push(b'\n');
+            input.extend_from_slice(&[b'>'; 40]);
+            input.extend_from_slice(b"From Alice\n");
+            let messages = read_all(MboxReader::new(input.as_slice()))?;
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0][..newline_idx + 1], input[..newline_idx + 1]);
+            assert_eq!(messages[0][newline_idx + 1..], input[newline_idx + 2..]);
+        }
+
+        // a run of `>` at EOF is returned as-is
+        let input = b"From a\n>>>>>>>>>>";
+        assert_eq!(read_all(MboxReader::new(&input[..]))?, [&input[..]]);
+        Ok(())
+    }
+
+    #[test]
+    fn body_to_string_lossy() -> io::Result<()> {
+        let input = b"From a\nSubject: hi\nTo: bob\n\nHello,\nBob \xff\nFrom b\nSubject: empty\n\nFrom c\nSubject: no blank line\n";
+        let mut reader = MboxReader::new(&input[..]);
+        assert_eq!(
+            reader.next()?.unwrap().body_to_string_lossy()?,
+            "Hello,\nBob \u{fffd}\n"
+        );
+        assert_eq!(reader.next()?.unwrap().body_to_string_lossy()?, "");
+        assert_eq!(reader.next()?.unwrap().body_to_string_lossy()?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn take_messages() -> io::Result<()> {
+        let input = b"From test1\ntest1\nFrom test2\ntest2\nFrom test3\ntest3\n";
+        let mut reader = MboxReader::new(io::Cursor::new(&input[..]));
+        let first_two = reader.take_messages(2).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(first_two, [&b"From test1\ntest1\n"[..], b"From test2\ntest2\n"]);
+        // only as far as the end of the second message has been read
+        assert_eq!(reader.current_boundary_offset()?, 33);
+
+        let rest = reader.take_messages(5).collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(rest, [b"From test3\ntest3\n"]);
+        assert_eq!(reader.take_messages(5).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn for_each_into() -> io::Result<()> {
+        let input = (0..1000)
+            .map(|i| format!("From test{}\n\nbody {}\n", i, i))
+            .collect::<String>();
+        let mut reader = MboxReader::new(input.as_bytes());
+        let mut buf = Vec::new();
+        let mut count = 0;
+        reader.for_each_into(&mut buf, |message| {
+            assert_eq!(message, format!("From test{}\n\nbody {}\n", count, count).as_bytes());
+            count += 1;
+            Ok(())
+        })?;
+        assert_eq!(count, 1000);
+        // big enough for one message, not for all of them
+        assert!(buf.capacity() < 256, "capacity {}", buf.capacity());
+
+        let mut reader = MboxReader::new(input.as_bytes());
+        let error = reader
+            .for_each_into(&mut buf, |_| Err(io::ErrorKind::Other.into()))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert!(reader.next_message()?.unwrap().starts_with(b"From test1\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn message_batches() -> io::Result<()> {
+        let input = (0..7)
+            .map(|i| format!("From test{}\n\nbody {}\n", i, i))
+            .collect::<String>();
+        let reader = MboxReader::new(input.as_bytes());
+        let batches = reader.message_batches(3).collect::<io::Result<Vec<_>>>()?;
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [3, 3, 1]);
+        assert_eq!(batches[2][0], b"From test6\n\nbody 6\n");
+        assert_eq!(batches.concat(), read_all(MboxReader::new(input.as_bytes()))?);
+
+        let reader = MboxReader::new(&input.as_bytes()[..input.len() / 7 * 6]);
+        assert_eq!(reader.message_batches(3).count(), 2);
+        assert_eq!(MboxReader::new(&b""[..]).message_batches(3).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn interleaved_fill_buf_and_read() -> io::Result<()> {
+        let pieces: [&[u8]; 5] = [b"text", b"\n", b"\nFrom x\n", b"Subject: s\n", b"\n\n"];
+        let mut rng = TestRng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..200 {
+            let mut input = b"From first\n".to_vec();
+            let target_len = rng.below(2 * DEFAULT_CAPACITY);
+            while input.len() < target_len {
+                let piece = pieces[rng.below(pieces.len())];
+                let repeat = if piece == b"text" { rng.below(200) } else { 1 };
+                for _ in 0..repeat {
+                    input.extend_from_slice(piece);
+                }
+            }
+            let mut reader = MboxReader::with_capacity(64 + rng.below(64), input.as_slice());
+            let mut messages: Vec<Vec<u8>> = Vec::new();
+            while let Some(mut item) = reader.next()? {
+                if rng.below(2) == 0 {
+                    // reading the head puts some of the message in a different buffer
+                    item.flags()?;
+                }
+                let mut msg = Vec::new();
+                loop {
+                    if rng.below(2) == 0 {
+                        let mut buf = vec![0; 1 + rng.below(100)];
+                        let n = item.read(&mut buf)?;
+                        if n == 0 {
+                            break;
+                        }
+                        msg.extend_from_slice(&buf[..n]);
+                    } else {
+                        let available = item.fill_buf()?;
+                        if available.is_empty() {
+                            break;
+                        }
+                        let amt = rng.below(available.len() + 1);
+                        msg.extend_from_slice(&available[..amt]);
+                        item.consume(amt);
+                    }
+                }
+                messages.push(msg);
+            }
+            assert_eq!(messages, reference_split(&input));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn from_line_len() -> io::Result<()> {
+        let input = b"From a@b.c Mon Jan  1 00:00:00 2001\r\nSubject: hi\n\nbody\nFrom d@e.f";
+        let mut reader = MboxReader::new(input.as_slice());
+
+        let mut item = reader.next()?.unwrap();
+        let len = item.from_line_len()?;
+        assert_eq!(len, 37);
+        io::copy(&mut item.by_ref().take(len as u64), &mut io::sink())?;
+        let mut rest = Vec::new();
+        item.read_to_end(&mut rest)?;
+        assert_eq!(rest, b"Subject: hi\n\nbody\n");
+
+        // just an envelope, with no newline
+        let mut item = reader.next()?.unwrap();
+        assert_eq!(item.from_line_len()?, 10);
+        rest.clear();
+        item.read_to_end(&mut rest)?;
+        assert_eq!(rest, b"From d@e.f");
+
+        let mut reader = MboxReader::new(b"Subject: no envelope\n\n".as_slice());
+        assert_eq!(reader.next()?.unwrap().from_line_len()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_token() -> io::Result<()> {
+        let input = b"From a\nfirst\n\nFrom b\n>From second\n\nFrom c\nX-Seq: 3\n".to_vec();
+        let mut reader = MboxReader::new(io::Cursor::new(input.clone()));
+        let mut item = reader.next()?.unwrap();
+        item.read_exact(&mut [0; 3])?;
+        let token = reader.resume_token()?;
+        assert_eq!(
+            token,
+            ResumeToken {
+                offset: 14,
+                message_number: 1,
+                file_len: input.len() as u64,
+            }
+        );
+        // taking a token doesn't disturb the reader
+        assert_eq!(reader.next_message()?.unwrap(), b"From b\nFrom second\n\n");
+
+        let mut resumed = MboxReader::resume(io::Cursor::new(input.clone()), &token)?;
+        assert_eq!(resumed.next_message()?.unwrap(), b"From b\nFrom second\n\n");
+        assert_eq!(resumed.resume_token()?.offset, 35);
+        assert_eq!(resumed.next_message()?.unwrap(), b"From c\nX-Seq: 3\n");
+        assert_eq!(resumed.next_message()?, None);
+        assert_eq!(
+            resumed.resume_token()?,
+            ResumeToken {
+                offset: input.len() as u64,
+                message_number: 3,
+                file_len: input.len() as u64,
+            }
+        );
+
+        // before anything has been read, and on a message found by skip_to_matching
+        let mut reader = MboxReader::new(io::Cursor::new(input.clone()));
+        assert_eq!(reader.resume_token()?.offset, 0);
+        assert!(reader.skip_to_matching(b"X-Seq", b"3")?);
+        let token = reader.resume_token()?;
+        assert_eq!((token.offset, token.message_number), (35, 2));
+        assert_eq!(reader.next_message()?.unwrap(), b"From c\nX-Seq: 3\n");
+        Ok(())
+    }
+
+    #[test]
+    fn resume_changed_file() {
+        let input = b"From a\nfirst\n\nFrom b\nsecond\n".to_vec();
+        let mut reader = MboxReader::new(io::Cursor::new(input.clone()));
+        reader.next().unwrap();
+        let token = reader.resume_token().unwrap();
+
+        let mut appended = input;
+        appended.extend_from_slice(b"\nFrom c\nthird\n");
+        let error = MboxReader::resume(io::Cursor::new(appended), &token).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn resume_token_as_text() -> io::Result<()> {
+        let input = b"From a\nfirst\n\nFrom b\nsecond\n".to_vec();
+        let mut reader = MboxReader::new(io::Cursor::new(input.clone()));
+        reader.next()?;
+        let saved = format!("{}\n", reader.resume_token()?);
+        assert_eq!(saved, "14 1 28\n");
+
+        let token: ResumeToken = saved.parse()?;
+        let mut resumed = MboxReader::resume(io::Cursor::new(input), &token)?;
+        assert_eq!(resumed.next_message()?.unwrap(), b"From b\nsecond\n");
+        assert_eq!(resumed.resume_token()?.message_number, 2);
+
+        for invalid in ["", "14 1", "14 1 28 0", "14 -1 28", "fourteen 1 28"] {
+            let error = invalid.parse::<ResumeToken>().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn blank_line_across_refill() -> io::Result<()> {
+        // every capacity up to the whole input, so each of the blank lines is split between fills
+        // at every point
+        let input = b"From a\r\nSubject: one\r\n\r\nbody\r\n\r\nFrom b\nSubject: two\n\nbody\n";
+        for capacity in MAGIC_WORD.len()..=input.len() {
+            let mut reader = MboxReader::with_capacity(capacity, input.as_slice());
+            assert_eq!(reader.next_header_block()?.unwrap(), b"From a\r\nSubject: one\r\n\r\n");
+            assert_eq!(reader.next_header_block()?.unwrap(), b"From b\nSubject: two\n\n");
+
+            let mut reader = MboxReader::with_capacity(capacity, input.as_slice());
+            let mut bodies = Vec::new();
+            while let Some(mut entry) = reader.next()? {
+                bodies.push(entry.body_to_string_lossy()?);
+            }
+            assert_eq!(bodies, ["body\r\n\r\n", "body\n"], "capacity {}", capacity);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn next_header_block() -> io::Result<()> {
+        let mut input = Vec::new();
+        for i in 0..50 {
+            input.extend_from_slice(format!("From {}\nSubject: {}\n", i, "s".repeat(i * 7)).as_bytes());
+            input.extend_from_slice(if i % 3 == 0 { b"\r\n" } else { b"\n" });
+            input.extend_from_slice("body\n".repeat(i).as_bytes());
+        }
+        input.extend_from_slice(b"From no body or blank line\nSubject: last\n");
+        // the allocating way
+        let expected: Vec<Vec<u8>> = read_all(MboxReader::new(input.as_slice()))?
+            .into_iter()
+            .map(|msg| msg[..head_len(&msg).unwrap_or(msg.len())].to_vec())
+            .collect();
+        assert_eq!(expected.len(), 51);
+
+        for capacity in [16, 64, 4096] {
+            let mut reader = MboxReader::with_capacity(capacity, input.as_slice());
+            let mut blocks = Vec::new();
+            while let Some(block) = reader.next_header_block()? {
+                blocks.push(block.to_vec());
+            }
+            assert_eq!(blocks, expected, "capacity {}", capacity);
+        }
+
+        // after skip_to_matching, the head has already been read
+        let mut reader = MboxReader::new(input.as_slice());
+        assert!(reader.skip_to_matching(b"Subject", b"sssssssss")?);
+        assert_eq!(reader.next_header_block()?, Some(expected[2].as_slice()));
+        assert_eq!(reader.next_header_block()?, Some(expected[3].as_slice()));
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_bytes() -> io::Result<()> {
+        let footer = b"-- \nArchived by listserv\n";
+        let mut input = b"From a\nSubject: 1\n\nbody\n\nFrom b\nContent-Length: 11\n\nbody\n\nmore\n\n".to_vec();
+        input.extend_from_slice(footer);
+
+        let mut reader = MboxReader::new(io::Cursor::new(input.clone()));
+        assert_eq!(
+            reader.trailing_bytes().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let messages: Vec<Vec<u8>> = reader.take_messages(usize::MAX).collect::<io::Result<_>>()?;
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].ends_with(footer), "still part of the last message");
+        assert_eq!(reader.trailing_bytes()?, footer);
+        assert!(reader.next()?.is_none());
+
+        // without a Content-Length the footer can't be recognised
+        let input = b"From a\n\nbody\n\n-- \nfooter\n";
+        let mut reader = MboxReader::new(io::Cursor::new(input.as_slice()));
+        while reader.next()?.is_some() {}
+        assert_eq!(reader.trailing_bytes()?, b"");
+
+        let mut reader = MboxReader::new(io::Cursor::new(b"".as_slice()));
+        assert!(reader.next()?.is_none());
+        assert_eq!(reader.trailing_bytes()?, b"");
+        Ok(())
+    }
+
+    #[test]
+    fn leading_separator() -> io::Result<()> {
+        let input = b"\nFrom a\nbody\nFrom b\r\nbody\r\nFrom c\r\n\nFrom d\n";
+        let expected = [
+            LeadingSeparator::None,
+            LeadingSeparator::Lf,
+            LeadingSeparator::CrLf,
+            LeadingSeparator::Lf,
+        ];
+        for capacity in [6, 7, 8, 64] {
+            let mut reader =
+                MboxReader::with_capacity(capacity, input.as_slice()).trim_leading(true);
+            let mut separators = Vec::new();
+            while let Some(mut item) = reader.next()? {
+                separators.push(item.leading_separator());
+                // whether or not the message was read first
+                if separators.len() % 2 == 0 {
+                    item.read_to_end(&mut Vec::new())?;
+                }
+            }
+            assert_eq!(separators, expected, "capacity {}", capacity);
+        }
+
+        let mut reader = MboxReader::new(io::Cursor::new(input.as_slice()));
+        for (offset, separator) in [
+            (0, LeadingSeparator::None),
+            (13, LeadingSeparator::Lf),
+            (27, LeadingSeparator::CrLf),
+            (36, LeadingSeparator::Lf),
+        ] {
+            reader.seek_to_message(offset)?;
+            assert_eq!(reader.next()?.unwrap().leading_separator(), separator);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn messages_since() -> io::Result<()> {
+        let mut file = io::Cursor::new(b"From a\n\nfirst\n\nFrom b\n\nsecond\n\n".to_vec());
+        let synced = MboxReader::new(&mut file)
+            .messages_since(0)
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(synced.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), [0, 15]);
+        let last_len = file.get_ref().len() as u64;
+
+        file.get_mut().extend_from_slice(b"From c\n\nthird\n\nFrom d\n\nfourth\n");
+        let mut reader = MboxReader::with_capacity(8, &mut file);
+        let new = reader
+            .messages_since(last_len)
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            new,
+            [
+                (31, b"From c\n\nthird\n\n".to_vec()),
+                (46, b"From d\n\nfourth\n".to_vec())
+            ]
+        );
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn index_with_escaped_headers() -> io::Result<()> {
+        let input = b"From a\n>From \n>From \nFrom b\n";
+        for capacity in [6, 7, 8, 11, 16, 4096] {
+            let mut reader = MboxReader::with_capacity(capacity, io::Cursor::new(input.as_slice()));
+            let index = reader.build_index_detailed()?;
+            let entries: Vec<_> =
+                index.entries().iter().map(|e| (e.offset, e.header_len)).collect();
+            assert_eq!(entries, [(0, 21), (21, 7)], "capacity {}", capacity);
+            assert_eq!(index.entries()[0].len, 21);
+            assert_eq!(reader.read_message_at(0)?, b"From a\nFrom \nFrom \n");
+
+            // offsets after looking ahead into the head, or past it
+            let mut reader = MboxReader::with_capacity(capacity, io::Cursor::new(input.as_slice()));
+            let mut entry = reader.next()?.unwrap();
+            entry.peek(16)?;
+            assert_eq!(entry.stable_id()?.split('#').next(), Some("0"));
+            assert_eq!(entry.body_len()?, 0);
+            assert!(reader.next()?.is_some());
+            assert_eq!(reader.last_start, Some(21));
+        }
+
+        // the body a Content-Length counts starts after the escaped header lines
+        let date = "Mon Jan  1 00:00:00 2001";
+        let input =
+            format!("From a {0}\n>From x\nContent-Length: 5\n\nbody\n\nFrom b {0}\n\n", date);
+        let mut reader = MboxReader::new(io::Cursor::new(input.as_bytes()))
+            .content_length(true)
+            .strict(true);
+        reader.next()?.unwrap().from_line()?;
+        assert!(reader.next_message()?.unwrap().starts_with(b"From b "));
+        Ok(())
+    }
+
+    #[test]
+    fn index_without_separator_lines() -> io::Result<()> {
+        // header-only messages, whose blank line is both the end of the head and the separator
+        let input = concat!(
+            "From a\r\nSubject: x\r\n\r\nFrom b\r\n\r\n",
+            "From c\r\nSubject: y\r\n\r\n\r\nFrom d\nSubject: z\n\n",
+            "From e\r\nSubject: w\r\n\r\nbody\r\n\r\nFrom f\r\n\r\n",
+        );
+        let kept = MboxReader::new(input.as_bytes()).build_index_detailed()?;
+        assert_eq!(kept.len(), 6);
+        for capacity in [6, 7, 8, 11, 16, 4096] {
+            let mut reader = MboxReader::with_capacity(capacity, io::Cursor::new(input.as_bytes()))
+                .keep_separator_line(false);
+            let index = reader.build_index_detailed()?;
+            let offsets: Vec<_> = index.entries().iter().map(|e| e.offset).collect();
+            let expected: Vec<_> = kept.entries().iter().map(|e| e.offset).collect();
+            assert_eq!(offsets, expected, "capacity {}", capacity);
+            for entry in index.entries() {
+                let message = reader.read_message_at(entry.offset)?;
+                let raw = &input.as_bytes()[entry.range().start as usize..][..entry.len as usize];
+                assert!(message.starts_with(b"From "), "capacity {}", capacity);
+                assert!(raw.starts_with(&message), "capacity {}", capacity);
+            }
+            assert_eq!(index.entries()[0].header_len, 22);
+
+            // and after looking ahead, or past the head
+            let mut reader = MboxReader::with_capacity(capacity, io::Cursor::new(input.as_bytes()))
+                .keep_separator_line(false);
+            let mut entry = reader.next()?.unwrap();
+            entry.peek(64)?;
+            assert_eq!(entry.stable_id()?.split('#').next(), Some("0"));
+            assert_eq!(entry.body_len()?, 0);
+            assert!(reader.next()?.is_some());
+            assert_eq!(reader.last_start, Some(22));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn goto() -> io::Result<()> {
+        let mut input = Vec::new();
+        for i in 0..2000 {
+            input.extend_from_slice(format!("From {}\nSubject: {}\n\n>From body\n\n", i, i).as_bytes());
+        }
+        let mut reader = MboxReader::new(io::Cursor::new(input));
+        let index = reader.build_index_detailed()?;
+        assert_eq!(index.len(), 2000);
+
+        assert!(reader.goto(&index, 1999)?);
+        assert_eq!(
+            reader.next_message()?.unwrap(),
+            b"From 1999\nSubject: 1999\n\nFrom body\n\n"
+        );
+        assert!(reader.next()?.is_none());
+        assert_eq!(reader.resume_token()?.message_number, 2000);
+
+        assert!(!reader.goto(&index, 2000)?);
+        assert!(reader.goto(&index, 0)?);
+        assert!(reader.next_message()?.unwrap().starts_with(b"From 0\n"));
+        assert!(reader.next_message()?.unwrap().starts_with(b"From 1\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_separator_line() -> io::Result<()> {
+        let input = b"From a\n\nbody\n\nFrom b\r\n\r\nbody\r\n\r\nFrom c\nno blank line\nFrom d\n\n\n\nFrom e\nlast\n\n";
+        let kept = read_all(MboxReader::new(input.as_slice()))?;
+        assert_eq!(
+            kept.iter().map(Vec::len).collect::<Vec<_>>(),
+            [14, 18, 21, 10, 13]
+        );
+        assert_eq!(kept.concat(), input);
+
+        for capacity in [6, 7, 8, 9, 10, 11, 16, 4096] {
+            let reader = MboxReader::with_capacity(capacity, input.as_slice()).keep_separator_line(false);
+            let dropped = read_all(reader)?;
+            assert_eq!(
+                dropped.iter().map(Vec::len).collect::<Vec<_>>(),
+                [13, 16, 21, 9, 13],
+                "capacity {}",
+                capacity
+            );
+            assert_eq!(dropped[0], b"From a\n\nbody\n");
+            assert_eq!(dropped[1], b"From b\r\n\r\nbody\r\n");
+            assert_eq!(dropped[3], b"From d\n\n\n");
+            assert_eq!(dropped[4], b"From e\nlast\n\n");
+        }
+
+        // reading exactly up to the dropped line, then moving on
+        let mut reader = MboxReader::new(input.as_slice()).keep_separator_line(false);
+        reader.next()?.unwrap().read_exact(&mut [0; 13])?;
+        assert_eq!(reader.next_message()?.unwrap(), b"From b\r\n\r\nbody\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_line_endings() -> io::Result<()> {
+        // LF bodies, with CRLF only around some of the envelope lines
+        let input = b"From a\nSubject: one\n\nbody\n\r\nFrom b\r\nSubject: two\n\nbody\r\nFrom c\r\n\nbody\n";
+        for capacity in [6, 7, 8, 9, 16, 4096] {
+            let kept = read_all(MboxReader::with_capacity(capacity, input.as_slice()))?;
+            assert_eq!(kept.concat(), input, "capacity {}", capacity);
+            assert_eq!(kept.len(), 3, "capacity {}", capacity);
+
+            let reader = MboxReader::with_capacity(capacity, input.as_slice()).keep_separator_line(false);
+            let dropped = read_all(reader)?;
+            assert_eq!(dropped[0], b"From a\nSubject: one\n\nbody\n");
+            assert_eq!(dropped[1], b"From b\r\nSubject: two\n\nbody\r\n");
+            assert_eq!(dropped[2], b"From c\r\n\nbody\n");
+        }
+
+        let mut reader = MboxReader::new(input.as_slice());
+        let mut entries = Vec::new();
+        while let Some(mut item) = reader.next()? {
+            entries.push((item.from_line()?.unwrap().sender, item.leading_separator()));
+        }
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), LeadingSeparator::None),
+                (b"b".to_vec(), LeadingSeparator::CrLf),
+                (b"c".to_vec(), LeadingSeparator::CrLf),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn split_into() -> io::Result<()> {
+        let mut input = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..10 {
+            let message = format!("From {0}\nSubject: {0}\n\n>From the body of {0}\n\n", i);
+            input.extend_from_slice(message.as_bytes());
+            expected.push(message.replace(">From", "From").into_bytes());
+        }
+        let mut shards = vec![Vec::new(); 3];
+        let mut shard_writers = shards.iter_mut();
+        let counts = MboxReader::new(input.as_slice())
+            .split_into(3, |_| Ok(shard_writers.next().unwrap()))?;
+        assert_eq!(counts, [4, 3, 3]);
+        assert_eq!(shards.concat().len(), input.len(), "nothing added or lost");
+
+        for (i, shard) in shards.iter().enumerate() {
+            let messages = read_all(MboxReader::new(shard.as_slice()))?;
+            let expected: Vec<_> = expected.iter().skip(i).step_by(3).cloned().collect();
+            assert_eq!(messages, expected);
+        }
+
+        let error = MboxReader::new(b"no envelope\n".as_slice())
+            .split_into(2, |_| Ok(io::sink()))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut reader = MboxReader::new(input.as_slice());
+        let error = reader.split_into(0, |_| Ok(io::sink())).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(reader.next()?.is_some(), "nothing read");
+        Ok(())
+    }
+
+    #[test]
+    fn skip_imap_header() -> io::Result<()> {
+        let pseudo = b"From MAILER_DAEMON Thu Jan  1 00:00:00 1970\nDate: Thu, 01 Jan 1970 00:00:00 +0000\nX-IMAPbase: 1234567890 0000000002\n\nThis text is part of the internal format of your mail folder.\n\n".to_vec();
+        let pseudo_first = [&b"From MAILER_DAEMON Mon Jan  1 00:00:00 2001\nX-IMAP: 1234567890 0000000002\n\n\n"[..], b"From a\nSubject: first\n\n"].concat();
+        let messages = b"From a\nSubject: first\n\nbody\n\nFrom b\nX-IMAP: not first\n\n".to_vec();
+
+        let with_pseudo = [pseudo.as_slice(), &messages].concat();
+        let skipped = read_all(MboxReader::new(with_pseudo.as_slice()).skip_imap_header(true))?;
+        assert_eq!(skipped, read_all(MboxReader::new(messages.as_slice()))?);
+        let mut reader = MboxReader::new(io::Cursor::new(&with_pseudo)).skip_imap_header(true);
+        reader.next()?;
+        assert_eq!(reader.resume_token()?.message_number, 1);
+
+        let skipped = read_all(MboxReader::new(pseudo_first.as_slice()).skip_imap_header(true))?;
+        assert_eq!(skipped, [b"From a\nSubject: first\n\n"]);
+        assert_eq!(read_all(MboxReader::new(with_pseudo.as_slice()))?.len(), 3, "off by default");
+
+        // only the first message is checked
+        let kept = read_all(MboxReader::new(messages.as_slice()).skip_imap_header(true))?;
+        assert_eq!(kept.len(), 2);
+        assert!(read_all(MboxReader::new(pseudo.as_slice()).skip_imap_header(true))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn stable_id() -> io::Result<()> {
+        let input = b"From a\nMessage-ID:  <one@example.com> \n\nbody\n\nFrom b\nSubject: no id\n\nbody\n";
+        let ids = |input: &[u8]| -> io::Result<Vec<String>> {
+            let mut reader = MboxReader::new(input);
+            let mut ids = Vec::new();
+            while let Some(mut item) = reader.next()? {
+                ids.push(item.stable_id()?);
+            }
+            Ok(ids)
+        };
+        let first = ids(input)?;
+        assert_eq!(first[0], "0:<one@example.com>");
+        assert_eq!(first[1], format!("46#{:016x}", fnv1a(b"From b\nSubject: no id\n\n")));
+        assert_eq!(ids(input)?, first, "the same every time");
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        // a different header gives a different hash
+        let changed = ids(b"From a\nMessage-ID:  <one@example.com> \n\nbody\n\nFrom b\nSubject: no ID\n\nbody\n")?;
+        assert_eq!(changed[0], first[0]);
+        assert_ne!(changed[1], first[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn messages_between() -> io::Result<()> {
+        let input = concat!(
+            "From a Thu Jan  1 00:00:00 1970\n\n1\n\n",
+            "From b Sat Feb 29 12:34:56 2020\n\n2\n\n",
+            "From c\n\nno date\n\n",
+            "From d Sun Mar  1 00:00:00 2020\n\n3\n\n",
+            "From e Mon Mar  2 00:00:00 2020\n\n4\n\n",
+            "From f Tue Mar  3 00:00:00 2020\n\n5\n",
+        );
+        let mut reader = MboxReader::new(input.as_bytes());
+        // from the start of 2020-02-29 up to, but not including, 2020-03-02
+        let found: Vec<Vec<u8>> = reader
+            .messages_between(1582934400, 1583107200)
+            .collect::<io::Result<_>>()?;
+        assert_eq!(
+            found,
+            [
+                &b"From b Sat Feb 29 12:34:56 2020\n\n2\n\n"[..],
+                b"From d Sun Mar  1 00:00:00 2020\n\n3\n\n",
+            ]
+        );
+        assert!(reader.next()?.is_none());
+
+        let count = |start, end| MboxReader::new(input.as_bytes()).messages_between(start, end).count();
+        assert_eq!(count(0, 1), 1);
+        assert_eq!(count(i64::MIN, i64::MAX), 5);
+        assert_eq!(count(1, 0), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn shrink_buffer() -> io::Result<()> {
+        let mut input = b"From big\n".to_vec();
+        for i in 0..400 {
+            input.extend_from_slice(format!(">From line {} of a big message\n", i).as_bytes());
+        }
+        for i in 0..20 {
+            input.extend_from_slice(format!("From {}\n\n>From small\n", i).as_bytes());
+        }
+        let expected = read_all(MboxReader::new(input.as_slice()))?;
+
+        for shrink_after in 0..40 {
+            let mut reader = MboxReader::with_capacity(64, io::Cursor::new(&input));
+            let mut messages = Vec::new();
+            let mut offsets = Vec::new();
+            while let Some(mut item) = reader.next()? {
+                let mut msg = Vec::new();
+                let mut buf = [0; 7];
+                loop {
+                    if msg.len() / 7 == shrink_after {
+                        // part way through a message, in the middle of what's buffered
+                        item.inner.shrink();
+                    }
+                    match item.read(&mut buf)? {
+                        0 => break,
+                        n => msg.extend_from_slice(&buf[..n]),
+                    }
+                }
+                messages.push(msg);
+                offsets.push(reader.current_boundary_offset()?);
+                // what has been read ahead of the current message is kept
+                reader.shrink_buffer();
+            }
+            assert_eq!(reader.buffer_capacity(), 64);
+            assert_eq!(messages, expected);
+            // the offsets still count the removed escapes
+            let mut expected_offsets = Vec::new();
+            let mut end = 0;
+            for msg in &expected {
+                end += msg.len() + msg.windows(6).filter(|w| w == b"\nFrom ").count();
+                expected_offsets.push(end as u64 - 1);
+            }
+            expected_offsets.pop();
+            offsets.pop();
+            assert_eq!(offsets, expected_offsets);
+        }
+
+        let mut reader = MboxReader::with_capacity(64, input.as_slice());
+        reader.next()?.unwrap().read_to_end(&mut Vec::new())?;
+        let grown = reader.buffer_capacity();
+        assert!(grown > 1024);
+        reader.shrink_buffer();
+        // the small messages after the big one were buffered along with it
+        assert!(reader.buffer_capacity() < grown && reader.buffer_capacity() > 64);
+        while reader.next()?.is_some() {}
+        reader.shrink_buffer();
+        assert_eq!(reader.buffer_capacity(), 64);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_messages() -> io::Result<()> {
+        let input = b"From a\n\n>From first\n\nFrom b\n\nsecond\n\nFrom c\n\nthird\n";
+        let mut reader = MboxReader::with_capacity(8, input.as_slice());
+        assert_eq!(reader.skip_messages(0)?, (0, 0));
+        assert_eq!(reader.skip_messages(1)?, (1, 21));
+        reader.next()?.unwrap().read_exact(&mut [0; 3])?;
+        // the rest of a message already begun isn't counted
+        assert_eq!(reader.skip_messages(5)?, (1, 14));
+        assert_eq!(reader.skip_messages(5)?, (0, 0));
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn with_total_limit() -> io::Result<()> {
+        // a stream which never ends, partway through a message
+        let inner = b"From a\n\nfirst\n\nFrom b\n\n".chain(io::repeat(b'x'));
+        let messages = read_all(MboxReader::with_total_limit(inner, 1000))?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], b"From a\n\nfirst\n\n");
+        assert_eq!(messages[1].len(), 1000 - 15);
+        assert!(messages[1].starts_with(b"From b\n\nxxx"));
+        Ok(())
+    }
+
+    #[test]
+    fn from_pipe() -> io::Result<()> {
+        let input = b"From a\n\nfirst\n\nFrom b\n\nsecond\n";
+        let mut reader = MboxReader::from_pipe(io::Cursor::new(input));
+        let mut count = 0;
+        while reader.next()?.is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 2);
+        assert_eq!(reader.last_message_had_trailing_newline()?, Some(true));
+        Ok(())
+    }
+
+    #[test]
+    fn new_bzip2() -> io::Result<()> {
+        // a stand-in for a real decoder, which just drops the header
+        fn decoder<R: Read>(mut compressed: R) -> R {
+            compressed.read_exact(&mut [0; 4]).unwrap();
+            compressed
+        }
+        let input = b"BZh9From a\n\nfirst\n\nFrom b\n\nsecond\n";
+        let reader = MboxReader::new_bzip2(input.as_slice(), decoder)?;
+        assert_eq!(read_all(reader)?, [&b"From a\n\nfirst\n\n"[..], b"From b\n\nsecond\n"]);
+
+        for input in [&b"From a\n\nnot compressed\n"[..], b"BZh0", b"BZ"] {
+            let error = MboxReader::new_bzip2(input, decoder).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn new_bounded() -> io::Result<()> {
+        let embedded = b"From a\n\nfirst\n\nFrom b\n\nsecond\n";
+        let mut input = b"header bytes".to_vec();
+        input.extend_from_slice(embedded);
+        input.extend_from_slice(b"\nFrom c\n\nnot part of it\n");
+
+        let mut inner = io::Cursor::new(&input);
+        inner.set_position(12);
+        let mut reader = MboxReader::new_bounded(inner, embedded.len() as u64);
+        assert_eq!(reader.next_message()?.unwrap(), b"From a\n\nfirst\n\n");
+        assert_eq!(reader.next_message()?.unwrap(), b"From b\n\nsecond\n");
+        assert_eq!(reader.next_message()?, None);
+        assert_eq!(reader.last_message_had_trailing_newline()?, Some(true));
+
+        let inner = reader.replace_inner(io::Cursor::new(&input).take(0)).into_inner();
+        assert_eq!(inner.position(), 12 + embedded.len() as u64, "nothing past the bound was read");
+        Ok(())
+    }
+
+    #[test]
+    fn filter_by_from() -> io::Result<()> {
+        let input = b"Not an envelope\n\nFrom alice@example.com Mon Jan  1 00:00:00 2001\n\n1\n\nFrom bob@example.com\n\n2\n\nFrom ALICE@example.com\n\n3\n";
+        let reader = MboxReader::new(input.as_slice());
+        let found: Vec<Vec<u8>> = reader
+            .filter_by_from(|line| line.sender.eq_ignore_ascii_case(b"alice@example.com"))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(
+            found,
+            [
+                &b"From alice@example.com Mon Jan  1 00:00:00 2001\n\n1\n\n"[..],
+                b"From ALICE@example.com\n\n3\n",
+            ]
+        );
+        let reader = MboxReader::new(input.as_slice());
+        assert_eq!(reader.filter_by_from(|_| true).count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn looks_like_mbox() -> io::Result<()> {
+        let mbox = b"\xEF\xBB\xBF\nFrom alice@example.com Mon Jan  1 00:00:00 2001\r\nSubject: hi\r\n\r\n";
+        let mut reader = MboxReader::new(mbox.as_slice()).trim_leading(true);
+        assert!(reader.looks_like_mbox()?);
+        assert!(reader.looks_like_mbox()?, "nothing consumed");
+        assert_eq!(reader.next_message()?.unwrap(), &mbox[4..]);
+
+        // after the head of the first message has been read
+        let mut reader = MboxReader::new(mbox.as_slice()).trim_leading(true);
+        reader.next()?.unwrap().flags()?;
+        reader.pending = true;
+        assert!(reader.looks_like_mbox()?);
+
+        let text = b"From the desk of the editor\nDear reader,\n";
+        assert!(MboxReader::new(text.as_slice()).looks_like_mbox()?, "can't be told apart");
+        assert!(!MboxReader::new(b"Dear reader,\nFrom me\n".as_slice()).looks_like_mbox()?);
+        assert!(!MboxReader::new(b"From \n".as_slice()).looks_like_mbox()?);
+        assert!(!MboxReader::new(b"From \x00\x01\x02\xFF binary\n".as_slice()).looks_like_mbox()?);
+        assert!(!MboxReader::new(b"\x7FELF\x02\x01\x01\x00".as_slice()).looks_like_mbox()?);
+        assert!(!MboxReader::new(b"".as_slice()).looks_like_mbox()?);
+        Ok(())
+    }
+
+    #[test]
+    fn read_range() -> io::Result<()> {
+        let input = b"From a\n\nfirst\n\nFrom b\n\n>From second\n>>From quoted\n\nFrom c\n\nthird".to_vec();
+        let expected = read_all(MboxReader::new(input.as_slice()))?;
+        let mut reader = MboxReader::new(io::Cursor::new(input));
+        let index = reader.build_index_detailed()?;
+        assert_eq!(index.get(1).unwrap().range(), MessageRange { start: 15, end: 51 });
+
+        let mut buf = Vec::new();
+        for (entry, expected) in index.entries().iter().zip(&expected).rev() {
+            buf.clear();
+            reader.read_range(&entry.range(), &mut buf)?;
+            assert_eq!(&buf, expected);
+            assert!(buf.capacity() >= entry.range().len() as usize);
         }
-        assert_eq!(0, reader.read(&mut buf).unwrap());
-        assert_eq!(&full, &input);
-        assert!(reader.eof().unwrap());
-        assert!(!reader.eom());
+        Ok(())
     }
 
     #[test]
-    fn crlf() {
-        // use CRLF line endings and check it works right
-        let input = b"From line1\r\nFrom line2\r\n";
-        let mut reader = MessageBoundaryReader::new(input.as_slice());
-        let mut line = String::new();
-        assert_eq!(reader.read_to_string(&mut line).unwrap(), 12);
-        assert!(reader.eom());
-        assert_eq!(line, "From line1\r\n");
-        reader.reset_eom();
-        line.clear();
-        assert_eq!(reader.read_to_string(&mut line).unwrap(), 12);
-        assert!(reader.eof().unwrap());
-        assert_eq!(line, "From line2\r\n");
+    fn into_parts() -> io::Result<()> {
+        let mut reader = MboxReader::new(
+            b"Subject: no envelope\n\nFrom a Mon Jan  1 00:00:00 2001\nSubject: one\n\nbody\n".as_slice(),
+        );
+        match reader.next()?.unwrap().into_parts() {
+            Err(error) => assert_eq!(error.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("first message has no envelope"),
+        }
+
+        let (from, mut rest) = reader.next()?.unwrap().into_parts()?;
+        assert_eq!(from.sender, b"a");
+        assert_eq!(from.timestamp(), Some(978307200));
+        let mut bytes = Vec::new();
+        rest.read_to_end(&mut bytes)?;
+        assert_eq!(bytes, b"Subject: one\n\nbody\n");
+        assert!(reader.next()?.is_none());
+        Ok(())
     }
 
     #[test]
-    fn exact_read_sizes() {
-        // read the exact number of bytes in the first line, then make sure it returns 0 bytes, then the next line
-        let input = b"From line1\nFrom line2";
-        let mut reader = MessageBoundaryReader::new(input.as_slice());
-        let mut buf: [u8; 11] = [0; 11];
-        let bytes_read = reader.read(&mut buf).unwrap();
-        assert_eq!(bytes_read, 11);
-        assert!(reader.eom());
-        assert_eq!(buf[..], input[..11]);
-        let bytes_read = reader.read(&mut buf).unwrap();
-        assert_eq!(bytes_read, 0);
-        reader.reset_eom();
-        assert!(!reader.eom());
-        let bytes_read = reader.read(&mut buf).unwrap();
-        assert_eq!(bytes_read, 10);
-        assert_eq!(buf[..bytes_read], input[11..]);
-        let bytes_read = reader.read(&mut buf).unwrap();
-        assert_eq!(bytes_read, 0);
-        assert!(reader.eof().unwrap());
-        assert!(!reader.eom());
+    fn skip_empty() -> io::Result<()> {
+        let input = b"From a\n\nFrom b\nFrom c\nSubject: one\n\nbody\n\nFrom d\n \r\n\t\nFrom e\n\n\n  \nsecond\n\nFrom f";
+        assert_eq!(read_all(MboxReader::new(input.as_slice()))?.len(), 6);
+
+        let messages = read_all(MboxReader::new(input.as_slice()).skip_empty(true))?;
+        assert_eq!(
+            messages,
+            [
+                &b"From c\nSubject: one\n\nbody\n\n"[..],
+                b"From e\n\n\n  \nsecond\n\n",
+            ]
+        );
+
+        let messages = read_all(
+            MboxReader::with_capacity(6, input.as_slice())
+                .max_buffer_capacity(6)
+                .skip_empty(true),
+        )?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1], b"From e\n\n\n  \nsecond\n\n");
+
+        // the whitespace read to check for emptiness is in the body, not the head
+        let mut reader = MboxReader::new(&input[54..]).skip_empty(true);
+        let body = reader.next()?.unwrap().body_to_string_lossy()?;
+        assert_eq!(body, "\n  \nsecond\n\n");
+        Ok(())
     }
 
     #[test]
-    fn page_boundary() {
-        // let's try a bunch of read sizes with the magic word just off the page boundary
-        for offset in 0..MAGIC_WORD.len() + 1 {
-            let mut input: Vec<u8> = vec![0; DEFAULT_CAPACITY];
-            for _ in 0..offset {
-                input.pop();
+    fn trim_trailing_blank_lines() -> io::Result<()> {
+        let input =
+            b"From a\nSubject: one\n\nbody\n\n \n\t\r\n\nFrom b\n\nsecond  \nlast\nFrom c\n\n\n";
+        let expected = [
+            &b"From a\nSubject: one\n\nbody\n"[..],
+            b"From b\n\nsecond  \nlast\n",
+            b"From c\n",
+        ];
+        for capacity in [6, 7, 8, 11, 16, DEFAULT_CAPACITY] {
+            let reader = MboxReader::with_capacity(capacity, input.as_slice())
+                .max_buffer_capacity(capacity.max(16))
+                .trim_trailing_blank_lines(true);
+            assert_eq!(read_all(reader)?, expected, "capacity {}", capacity);
+        }
+        let messages = read_all(
+            MboxReader::new(input.as_slice())
+                .trim_trailing_blank_lines(true)
+                .keep_separator_line(false),
+        )?;
+        assert_eq!(messages, expected);
+
+        // a body which ends partway through a line keeps it
+        let mut reader =
+            MboxReader::new(b"From a\n\ntext  ".as_slice()).trim_trailing_blank_lines(true);
+        assert_eq!(reader.next_message()?.unwrap(), b"From a\n\ntext  ");
+
+        // the trimmed bytes aren't returned, but they are still part of the stream
+        for capacity in [6, 7, 8, 11, 16, DEFAULT_CAPACITY] {
+            let mut reader = MboxReader::with_capacity(capacity, io::Cursor::new(&input[..]))
+                .max_buffer_capacity(capacity.max(16))
+                .trim_trailing_blank_lines(true);
+            let index = reader.build_index_detailed()?;
+            let last = index.get(2).unwrap();
+            assert_eq!(last.offset + last.len, input.len() as u64, "capacity {}", capacity);
+            assert_eq!(reader.read_message_at(last.offset)?, expected[2]);
+            assert_eq!(reader.current_boundary_offset()?, input.len() as u64);
+        }
+        let ranges = MboxReader::new(input.as_slice())
+            .trim_trailing_blank_lines(true)
+            .messages_with_ranges()
+            .map(|item| item.map(|(range, _)| range.end))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(ranges, [33, 55, input.len() as u64]);
+        Ok(())
+    }
+
+    #[test]
+    fn synthesize_missing_envelopes() -> io::Result<()> {
+        let input = b"Subject: no envelope\n\nbody\n\nFrom b\n\nsecond\n";
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let mut reader = MboxReader::new(input.as_slice()).synthesize_missing_envelopes(true);
+        let mut entry = reader.next()?.unwrap();
+        let from = entry.from_line()?.unwrap();
+        assert_eq!(from.sender, b"MAILER-DAEMON");
+        let date = from.timestamp().unwrap();
+        assert!(date >= before && date <= before + 60);
+        let mut message = Vec::new();
+        entry.read_to_end(&mut message)?;
+        let envelope_len = memchr(b'\n', &message).unwrap() + 1;
+        assert!(message.starts_with(b"From MAILER-DAEMON "));
+        assert_eq!(message[envelope_len..], input[..28]);
+        assert_eq!(reader.next_message()?.unwrap(), b"From b\n\nsecond\n");
+
+        // offsets are still those in the stream
+        let reader = MboxReader::new(input.as_slice()).synthesize_missing_envelopes(true);
+        let ranges = reader
+            .messages_with_ranges()
+            .map(|next| next.map(|(range, _)| (range.start, range.end)))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(ranges, [(0, 28), (28, 43)]);
+
+        let messages = read_all(MboxReader::new(&input[28..]).synthesize_missing_envelopes(true))?;
+        assert_eq!(messages, [&input[28..]]);
+        assert_eq!(read_all(MboxReader::new(input.as_slice()))?[0], input[..28]);
+        Ok(())
+    }
+
+    #[test]
+    fn count_by_sender() -> io::Result<()> {
+        let input = b"From alice@example.com Mon Jan  1 00:00:00 2001\nFrom: Alice <alice@example.com>\n\nFrom: in the body\n\nFrom bob@example.com\nFrom: bob@example.com\n\n\nFrom alice@example.com\nFrom: \"Alice, again\" <alice@example.org>\n\nbody\n";
+        let counts = MboxReader::new(input.as_slice()).count_by_sender(SenderSource::Envelope)?;
+        let expected = HashMap::from([
+            (b"alice@example.com".to_vec(), 2),
+            (b"bob@example.com".to_vec(), 1),
+        ]);
+        assert_eq!(counts, expected);
+
+        let counts = MboxReader::new(input.as_slice()).count_by_sender(SenderSource::FromHeader)?;
+        let expected = HashMap::from([
+            (b"alice@example.com".to_vec(), 1),
+            (b"alice@example.org".to_vec(), 1),
+            (b"bob@example.com".to_vec(), 1),
+        ]);
+        assert_eq!(counts, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn charset() -> io::Result<()> {
+        let input = b"From a\nContent-Type: text/plain; charset=\"UTF-8\"; format=flowed\n\nbody\n\nFrom b\ncontent-type: text/html;\n  Charset=ISO-8859-1\n\nFrom c\nContent-Type: text/plain\n\nFrom d\n\n";
+        let mut reader = MboxReader::new(input.as_slice());
+        let mut charsets = Vec::new();
+        while let Some(mut entry) = reader.next()? {
+            charsets.push(entry.charset()?);
+            let mut message = Vec::new();
+            entry.read_to_end(&mut message)?;
+            // the head is still returned
+            assert!(message.starts_with(b"From "));
+        }
+        assert_eq!(
+            charsets,
+            [Some("utf-8".to_string()), Some("iso-8859-1".to_string()), None, None]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn body_len() -> io::Result<()> {
+        let input = b"From a\nSubject: 1\n\nbody\n\nFrom b\r\nSubject: 2\r\n\r\n>From body\r\nFrom c\nSubject: no body\nFrom d\n\n\n";
+        let index = MboxReader::new(input.as_slice()).build_index_detailed()?;
+        for keep_separator_line in [true, false] {
+            let mut reader = MboxReader::with_capacity(8, input.as_slice())
+                .keep_separator_line(keep_separator_line);
+            let mut lens = Vec::new();
+            while let Some(mut entry) = reader.next()? {
+                lens.push(entry.body_len()?);
             }
-            input.extend_from_slice(&MAGIC_WORD);
-            input.extend_from_slice(b"test123");
-            let input = input;
+            let expected: Vec<u64> = index
+                .entries()
+                .iter()
+                .map(|entry| entry.len - entry.header_len)
+                .collect();
+            assert_eq!(lens, expected);
+            assert_eq!(lens, [6, 12, 0, 1]);
+        }
+        Ok(())
+    }
 
-            for block_size in 0..32 {
-                let mut buffer: Vec<u8> = vec![0; 128 + block_size];
-                let mut result: Vec<u8> = Vec::with_capacity(DEFAULT_CAPACITY + 16);
-                let mut reader = MessageBoundaryReader::new(input.as_slice());
-                while !reader.eom() {
-                    let read = reader.read(&mut buffer).unwrap();
-                    assert!(read > 0);
-                    // if read == 0 {
-                    //     let eom = reader.eom();
-                    //     let eof = reader.eof().unwrap();
-                    //     println!("{}, {}", eom, eof);
-                    // }
-                    result.extend_from_slice(&buffer[..read]);
+    /// The malformation `result` reports.
+    fn malformation<T>(result: io::Result<T>) -> MboxError {
+        match result {
+            Ok(_) => panic!("no error"),
+            Err(e) => e.get_ref().unwrap().downcast_ref::<MboxError>().unwrap().clone(),
+        }
+    }
+
+    #[test]
+    fn strict() -> io::Result<()> {
+        const DATE: &str = "Mon Jan  1 00:00:00 2001";
+        let message = |envelope: &str, rest: &str| format!("From {}\n{}", envelope, rest);
+        let good = |sender: &str| message(&format!("{} {}", sender, DATE), "\nbody\n\n");
+
+        let no_envelope = "Subject: none\n\nbody\n\n".to_string();
+        for bad in [no_envelope, message("", "\n\n"), message("a", "\n\n")] {
+            let input = bad + &good("b");
+            let mut reader = MboxReader::new(input.as_bytes()).strict(true);
+            assert_eq!(malformation(reader.next()), MboxError::BadEnvelope { message: 0 });
+            assert!(reader.next_message()?.unwrap().starts_with(b"From b "));
+            assert!(reader.next()?.is_none());
+        }
+
+        let input = message(&format!("a {}", DATE), "\nno blank line\n") + &good("b");
+        let mut reader = MboxReader::new(input.as_bytes()).strict(true);
+        reader.next_message()?.unwrap();
+        let error = malformation(reader.next());
+        assert_eq!(error, MboxError::MissingBlankLine { message: 0 });
+        assert_eq!(error.to_string(), "message 0 isn't followed by a blank line");
+        assert!(reader.next_message()?.unwrap().starts_with(b"From b "));
+        assert!(reader.next()?.is_none());
+        // the last message needn't end with one
+        let mut reader = MboxReader::new(&input.as_bytes()[..input.len() - 1]).strict(true);
+        assert_eq!(reader.take_messages(3).filter(Result::is_err).count(), 1);
+
+        let with_length = |length: &str, body: &str| {
+            message(&format!("a {}", DATE), &format!("Content-Length: {}\n\n{}", length, body))
+        };
+        for (length, body, body_len) in [("5", "body\n\n\n", Some(7)), ("x", "body\n\n", None)] {
+            let input = with_length(length, body) + &with_length(length, body);
+            let mut reader = MboxReader::new(input.as_bytes()).strict(true);
+            reader.next()?.unwrap();
+            let error = malformation(reader.next());
+            assert_eq!(error.message(), 0);
+            if let Some(body_len) = body_len {
+                let expected = MboxError::ContentLengthMismatch {
+                    message: 0,
+                    content_length: Some(5),
+                    body_len,
+                };
+                assert_eq!(error, expected);
+            }
+            assert_eq!(reader.next()?.unwrap().from_line()?.unwrap().sender, b"a");
+            assert_eq!(malformation(reader.next()).message(), 1);
+            assert!(reader.next()?.is_none());
+        }
+        let input = with_length("5", "body\n\n") + &with_length("5", "body\n");
+        let messages = read_all(MboxReader::new(input.as_bytes()).strict(true))?;
+        assert_eq!(messages.len(), 2);
+
+        // when skipping by Content-Length too
+        let input = with_length("5", "body\n") + &good("b");
+        let mut reader = MboxReader::new(io::Cursor::new(input.as_bytes()))
+            .content_length(true)
+            .strict(true);
+        reader.next()?.unwrap();
+        assert_eq!(malformation(reader.next()), MboxError::MissingBlankLine { message: 0 });
+        assert!(reader.next_message()?.unwrap().starts_with(b"From b "));
+        Ok(())
+    }
+
+    #[test]
+    fn copy_to() -> io::Result<()> {
+        let input = b"From a\nSubject: one\n\n>From the body\n\nFrom b\n\nsecond\n";
+        let mut reader = MboxReader::with_capacity(8, input.as_slice());
+        let mut entry = reader.next()?.unwrap();
+        entry.from_line()?;
+        let mut copied = Vec::new();
+        assert_eq!(entry.copy_to(&mut copied)?, 36);
+        assert_eq!(copied, b"From a\nSubject: one\n\nFrom the body\n\n");
+        assert_eq!(reader.next_message()?.unwrap(), b"From b\n\nsecond\n");
+        Ok(())
+    }
+
+    #[test]
+    fn read_teeing() -> io::Result<()> {
+        let input = b"From a\nSubject: one\n\n>From the body\n\nFrom b\n\nsecond\n";
+        let direct = read_all(MboxReader::new(input.as_slice()))?;
+        let mut reader = MboxReader::with_capacity(8, input.as_slice());
+        for expected in direct {
+            let mut entry = reader.next()?.unwrap();
+            let (mut read, mut tee) = (Vec::new(), Vec::new());
+            let mut buf = [0; 5];
+            loop {
+                match entry.read_teeing(&mut buf, &mut tee)? {
+                    0 => break,
+                    n => read.extend_from_slice(&buf[..n]),
                 }
-                // 1 byte for the newline, plus the total number of null bytes
-                assert_eq!(result.len(), 1 + DEFAULT_CAPACITY - offset);
-                assert_eq!(*result.last().unwrap(), b'\n');
-                let read = reader.read(&mut buffer).unwrap();
-                assert_eq!(read, 0);
-                reader.reset_eom();
+            }
+            assert_eq!(read, expected);
+            assert_eq!(tee, expected);
+        }
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
 
-                let mut v: Vec<u8> = Vec::new();
-                reader.read_to_end(&mut v).unwrap();
-                assert!(reader.eof().unwrap());
-                assert_eq!(b"From test123", &v[..]);
+    #[test]
+    fn find_duplicate_message_ids() -> io::Result<()> {
+        let input = b"From a\nMessage-ID: <one@x>\n\nfirst\n\nFrom b\nMessage-ID: <two@x>\n\n\nFrom c\nSubject: no id\n\n\nFrom d\nmessage-id:  <one@x>\n\nagain\n";
+        let mut reader = MboxReader::new(input.as_slice());
+        assert_eq!(
+            reader.find_duplicate_message_ids()?,
+            [(b"<one@x>".to_vec(), vec![0, 88])]
+        );
+        assert!(MboxReader::new(&input[..68])
+            .find_duplicate_message_ids()?
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn file_fingerprint() -> io::Result<()> {
+        let input = b"From a\n\nfirst\n\nFrom b\n\nsecond\n".to_vec();
+        let mut reader = MboxReader::with_capacity(8, io::Cursor::new(input.clone()));
+        let mut entry = reader.next()?.unwrap();
+        let mut first = Vec::new();
+        entry.read_to_end(&mut first)?;
+        let fingerprint = reader.file_fingerprint()?;
+        assert_eq!(fingerprint, reader.file_fingerprint()?);
+        assert_eq!(reader.next_message()?.unwrap(), b"From b\n\nsecond\n");
+        assert_eq!(fingerprint, reader.file_fingerprint()?);
+
+        let mut changed = input;
+        changed[17] = b'X';
+        let mut reader = MboxReader::new(io::Cursor::new(changed));
+        assert_ne!(reader.file_fingerprint()?, fingerprint);
+        Ok(())
+    }
+
+    #[test]
+    fn separator_lengths() -> io::Result<()> {
+        let mut rng = TestRng(0xd1b5_4a32_d192_ed03);
+        for separator in [&b"\n#"[..], b"\nFrom ", b"\nMESSAGE: "] {
+            let len = separator.len();
+            let pieces = [
+                &b"x"[..],
+                b"\n",
+                separator,
+                &separator[..len - 1],
+                &separator[1..],
+            ];
+            // the smallest buffers put a boundary at or across an edge on most refills (the
+            // buffer can't start out smaller than the default magic word, though)
+            for capacity in [len, len + 1, 2 * len - 1, 16] {
+                let capacity = capacity.max(MAGIC_WORD.len());
+                for _ in 0..50 {
+                    let target_len = rng.below(8 * capacity);
+                    let mut input = Vec::new();
+                    while input.len() < target_len {
+                        input.extend_from_slice(pieces[rng.below(pieces.len())]);
+                    }
+
+                    let mut reader = MessageBoundaryReader::with_capacity(capacity, input.as_slice());
+                    reader.max_capacity = capacity;
+                    reader.dialect = Dialect::Raw;
+                    reader.set_separator(separator);
+                    let mut messages = Vec::new();
+                    loop {
+                        let mut message = Vec::new();
+                        reader.read_to_end(&mut message)?;
+                        if message.is_empty() && !reader.eom() {
+                            break;
+                        }
+                        messages.push(message);
+                        if !reader.eom() {
+                            break;
+                        }
+                        reader.reset_eom();
+                    }
+                    assert_eq!(
+                        messages,
+                        reference_split_on(&input, separator),
+                        "separator {:?}, capacity {}, input {:?}",
+                        String::from_utf8_lossy(separator),
+                        capacity,
+                        String::from_utf8_lossy(&input)
+                    );
+                }
             }
         }
+        Ok(())
     }
 
     #[test]
-    fn mbox_reader() -> io::Result<()> {
-        let raw_messages = [
-            b"From test1\ntest1\n",
-            b"From test2\ntest2\n",
-            b"From test3\ntest3\n",
+    fn lines_with_quote_depth() -> io::Result<()> {
+        let input = b"From a\nSubject: quotes\n\n>From one\n>>From two\n>>>>From four\n>not From\n";
+        let depths = |dialect: Dialect| -> io::Result<Vec<(usize, Vec<u8>)>> {
+            let mut reader = MboxReader::with_capacity(8, input.as_slice()).dialect(dialect);
+            let mut entry = reader.next()?.unwrap();
+            entry.lines_with_quote_depth().collect()
+        };
+        let lines = depths(Dialect::Mboxrd)?;
+        let expected: [(usize, &[u8]); 7] = [
+            (0, b"From a"),
+            (0, b"Subject: quotes"),
+            (0, b""),
+            (1, b"From one"),
+            (2, b">From two"),
+            (4, b">>>From four"),
+            (0, b">not From"),
         ];
-        let input: Vec<u8> = raw_messages
-            .iter()
-            .map(|&x| x.iter().copied())
-            .flatten()
-            .collect();
+        assert_eq!(lines.len(), expected.len());
+        for ((depth, line), (expected_depth, expected_line)) in lines.iter().zip(expected) {
+            assert_eq!((*depth, line.as_slice()), (expected_depth, expected_line));
+        }
+
+        let lines = depths(Dialect::Mboxo)?;
+        let depths_only: Vec<usize> = lines.iter().map(|(depth, _)| *depth).collect();
+        assert_eq!(depths_only, [0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(lines[4].1, b">>From two");
+        assert!(depths(Dialect::Raw)?.iter().all(|(depth, _)| *depth == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn lines_with_offsets() -> io::Result<()> {
+        let input = b"From a\nSubject: one\r\n\nbody\n\n\nlast line\n\nFrom b\n\nno newline";
         let mut reader = MboxReader::new(input.as_slice());
-        let mut messages: Vec<String> = Vec::new();
-        while let Some(mut item) = reader.next()? {
-            let mut msg = String::new();
-            assert_eq!(item.read_to_string(&mut msg)?, 17);
-            messages.push(msg);
+        let lines = reader
+            .next()?
+            .unwrap()
+            .lines_with_offsets()
+            .collect::<io::Result<Vec<_>>>()?;
+        let expected: [(u64, &[u8]); 8] = [
+            (0, b"From a"),
+            (7, b"Subject: one"),
+            (21, b""),
+            (22, b"body"),
+            (27, b""),
+            (28, b""),
+            (29, b"last line"),
+            (39, b""),
+        ];
+        assert_eq!(lines.len(), expected.len());
+        for ((offset, line), (expected_offset, expected_line)) in lines.iter().zip(expected) {
+            assert_eq!((*offset, line.as_slice()), (expected_offset, expected_line));
         }
-        let message_bytes: Vec<&[u8]> = messages.iter().map(|x| x.as_bytes()).collect();
-        assert_eq!(&raw_messages[..], message_bytes);
+
+        let mut entry = reader.next()?.unwrap();
+        let lines = entry.lines_with_offsets().collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            [
+                (0, b"From b".to_vec()),
+                (7, Vec::new()),
+                (8, b"no newline".to_vec())
+            ]
+        );
+        Ok(())
+    }
+
+    /// Returns at most `max` bytes per read, and `WouldBlock` before every read which returns any.
+    struct NonBlocking<'a> {
+        data: &'a [u8],
+        max: usize,
+        ready: bool,
+    }
+
+    impl Read for NonBlocking<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.ready && !self.data.is_empty() {
+                self.ready = true;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.ready = false;
+            let n = buf.len().min(self.max).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    /// A reader which never has anything ready.
+    struct Stalled;
+
+    impl Read for Stalled {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn read_with_deadline() -> io::Result<()> {
+        let input = b"From a\n\nfirst\n\nFrom b\n\nsecond\n";
+        let mut reader = MboxReader::with_capacity(
+            8,
+            NonBlocking {
+                data: input,
+                max: 3,
+                ready: false,
+            },
+        );
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut message = Vec::new();
+        let mut entry = loop {
+            match reader.next() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                next => break next?.unwrap(),
+            }
+        };
+        let mut buf = [0; 4];
+        loop {
+            match entry.read_with_deadline(&mut buf, deadline)? {
+                0 => break,
+                n => message.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(message, b"From a\n\nfirst\n\n");
+
+        // the first fill of the buffer is enough to start the message
+        let mut reader = MboxReader::with_capacity(8, b"From a\nthen stalls".chain(Stalled));
+        let mut entry = reader.next()?.unwrap();
+        let mut buf = [0; 64];
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(20);
+        let err = loop {
+            match entry.read_with_deadline(&mut buf, deadline) {
+                Ok(n) => assert!(n > 0),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(Instant::now() >= deadline);
+        Ok(())
+    }
+
+    #[test]
+    fn would_block() -> io::Result<()> {
+        let input = b"From a\n\nfirst\n\nFrom b\n\n>From second\nFrom c\n\nthird\n";
+        let expected = read_all(MboxReader::new(input.as_slice()))?;
+        for max in 1..8 {
+            let mut reader = MboxReader::with_capacity(
+                8,
+                NonBlocking {
+                    data: input,
+                    max,
+                    ready: false,
+                },
+            );
+            let mut messages = Vec::new();
+            loop {
+                let mut entry = match reader.next() {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                };
+                let mut message = Vec::new();
+                let mut buf = [0; 4];
+                loop {
+                    match entry.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => message.extend_from_slice(&buf[..n]),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                let at_boundary = entry.at_boundary();
+                messages.push(message);
+                assert_eq!(at_boundary, messages.len() < expected.len(), "max {}", max);
+            }
+            assert_eq!(messages, expected, "max {}", max);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn extract_attachments() -> io::Result<()> {
+        let input = b"From a\nContent-Type: multipart/mixed; boundary=\"XYZ\"\n\n--XYZ\nContent-Type: text/plain\n\nsee attached\n--XYZ\nContent-Type: text/plain; name=ignored.txt\nContent-Disposition: attachment; filename=\"../hello.txt\"\nContent-Transfer-Encoding: base64\n\nSGVsbG8sIHdv\ncmxkIQo=\n--XYZ--\n\nFrom b\n\nno attachments\n";
+        let dir = std::env::temp_dir().join(format!("mbox-indexer-attachments-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let mut reader = MboxReader::new(input.as_slice());
+
+        let paths = reader.next()?.unwrap().extract_attachments(&dir)?;
+        assert_eq!(paths, [dir.join("hello.txt")]);
+        assert_eq!(std::fs::read(&paths[0])?, b"Hello, world!\n");
+        assert!(reader.next()?.unwrap().extract_attachments(&dir)?.is_empty());
+
+        // a second copy doesn't overwrite the first
+        let paths = MboxReader::new(input.as_slice()).next()?.unwrap().extract_attachments(&dir)?;
+        assert_eq!(paths, [dir.join("hello-1.txt")]);
+        std::fs::remove_dir_all(&dir)
+    }
+
+    /// Counts the reads made of it.
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl Read for CountingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn next_after_full_read() -> io::Result<()> {
+        // the first message is exactly as long as the buffer, so it ends right at a refill
+        let input = b"From a\n\nbody...\nFrom b\n\nsecond\n";
+        let counting = CountingReader {
+            data: input,
+            reads: 0,
+        };
+        let mut reader = MboxReader::with_capacity(16, counting).max_buffer_capacity(16);
+        let mut first = [0; 16];
+        reader.next()?.unwrap().read_exact(&mut first)?;
+        assert_eq!(&first, b"From a\n\nbody...\n");
+
+        let reads = reader.inner.inner.reads;
+        let from = reader.next()?.unwrap().from_line()?.unwrap();
+        assert_eq!(from.sender, b"b");
+        assert_eq!(reader.inner.inner.reads, reads, "nothing more was read");
+        Ok(())
+    }
+
+    #[test]
+    fn headers_at() -> io::Result<()> {
+        let input = b"From a\nSubject: one\n\nfirst\n\nFrom b\nSubject: two\nTo: x@example.com\n\nthe body\n\nFrom c\nSubject: three\n\n".to_vec();
+        let mut reader = MboxReader::with_capacity(16, io::Cursor::new(input));
+        let index = reader.build_index_detailed()?;
+        let headers = reader.headers_at(index.get(1).unwrap().offset)?;
+        assert_eq!(
+            headers,
+            [
+                (b"Subject".to_vec(), b"two".to_vec()),
+                (b"To".to_vec(), b"x@example.com".to_vec())
+            ]
+        );
+        assert_eq!(reader.next_message()?.unwrap(), b"From c\nSubject: three\n\n");
+        assert_eq!(reader.headers_at(index.get(0).unwrap().offset)?[0].1, b"one");
+        Ok(())
+    }
+
+    #[test]
+    fn messages_without_envelopes() -> io::Result<()> {
+        let input = concat!(
+            "Subject: no envelope\n\nfirst\n\n",
+            "From a\r\nSubject: a\r\n\r\n>From body\r\nFrom b",
+        );
+        let messages = MboxReader::new(input.as_bytes())
+            .messages_without_envelopes()
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            messages,
+            [
+                &b"Subject: no envelope\n\nfirst\n\n"[..],
+                b"Subject: a\r\n\r\nFrom body\r\n",
+                b"",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn build_index() -> io::Result<()> {
+        let input = b"From a\n\n>From one\n\nFrom b\r\n\r\ntwo\r\nFrom c\n".to_vec();
+        let mut reader = MboxReader::new(io::Cursor::new(input));
+        let offsets = reader.build_index()?;
+        assert_eq!(offsets, [0, 19, 34]);
+        assert_eq!(reader.read_message_at(19)?, b"From b\r\n\r\ntwo\r\n");
+        // only the rest of the stream
+        assert_eq!(reader.build_index()?, [34]);
+        Ok(())
+    }
+
+    #[test]
+    fn build_index_sorted_by_date() -> io::Result<()> {
+        let input = b"From a Sat Feb 29 12:34:56 2020\n\none\n\nFrom b yesterday\n\ntwo\n\nFrom c Mon Jan  1 00:00:00 2001\n\nthree\n\nFrom d\n\nfour\n\nFrom e Sat Feb 29 12:34:56 2020\n\nfive\n".to_vec();
+        let mut reader = MboxReader::new(io::Cursor::new(input));
+        let offsets = reader.build_index_sorted_by_date()?;
+        let order = offsets
+            .iter()
+            .map(|&offset| Ok(reader.read_message_at(offset)?[5]))
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(order, b"caebd");
+        Ok(())
+    }
+
+    /// A `Cursor` which counts the reads made of it.
+    struct CountingCursor {
+        cursor: io::Cursor<Vec<u8>>,
+        reads: usize,
+    }
+
+    impl Read for CountingCursor {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.cursor.read(buf)
+        }
+    }
+
+    impl Seek for CountingCursor {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.cursor.seek(pos)
+        }
+    }
+
+    #[test]
+    fn content_length() -> io::Result<()> {
+        let body = "a line of the body\n".repeat(200);
+        let mut input = String::new();
+        for (sender, length) in [("a", body.len()), ("b", body.len()), ("c", 7), ("d", body.len())] {
+            input += &format!("From {}\r\nContent-Length: {}\r\n\r\n{}\n", sender, length, body);
+        }
+        input += "From e\n\nlast\n";
+
+        let mut reads = Vec::new();
+        for use_content_length in [false, true] {
+            let counting = CountingCursor {
+                cursor: io::Cursor::new(input.clone().into_bytes()),
+                reads: 0,
+            };
+            let mut reader = MboxReader::with_capacity(256, counting)
+                .max_buffer_capacity(256)
+                .content_length(use_content_length);
+            let mut senders = Vec::new();
+            let mut offsets = Vec::new();
+            while let Some(mut entry) = reader.next()? {
+                let sender = entry.from_line()?.unwrap().sender;
+                if sender == b"d" || sender == b"e" {
+                    let mut message = Vec::new();
+                    entry.read_to_end(&mut message)?;
+                    assert!(message.starts_with(b"From "));
+                    assert!(message.ends_with(if sender == b"d" { b"body\n\n" } else { b"last\n" }));
+                }
+                senders.push(sender);
+                offsets.push(reader.last_start.unwrap());
+            }
+            assert_eq!(senders, [b"a", b"b", b"c", b"d", b"e"]);
+            let expected = input.match_indices("From ").map(|(i, _)| i as u64);
+            assert_eq!(offsets, expected.collect::<Vec<_>>());
+            reads.push(reader.inner.inner.reads);
+        }
+        // the message with the wrong length, and the ones which are read, are still scanned
+        assert!(reads[1] * 3 < reads[0] * 2, "{:?}", reads);
+        Ok(())
+    }
+
+    #[test]
+    fn peek() -> io::Result<()> {
+        let input = b"From a\nContent-Type: application/pdf\n\n%PDF-1.4 and the rest of the document\n\nFrom b\n\nshort\n";
+        let mut reader = MboxReader::with_capacity(16, input.as_slice()).max_buffer_capacity(16);
+        let mut entry = reader.next()?.unwrap();
+        assert_eq!(entry.peek(4)?, b"From");
+        assert_eq!(entry.peek(30)?, b"From a\nContent-Type: applicati");
+        entry.skip_head()?;
+        assert_eq!(entry.peek(4)?, b"%PDF");
+        let mut body = Vec::new();
+        entry.read_to_end(&mut body)?;
+        assert_eq!(body, b"%PDF-1.4 and the rest of the document\n\n");
+
+        // spanning bytes which have been read into the head and ones which haven't
+        let mut entry = reader.next()?.unwrap();
+        assert_eq!(entry.from_line()?.unwrap().sender, b"b");
+        assert_eq!(entry.peek(100)?, b"From b\n\nshort\n");
+        assert_eq!(entry.peek(9)?, b"From b\n\ns");
+        let mut message = Vec::new();
+        entry.read_to_end(&mut message)?;
+        assert_eq!(message, b"From b\n\nshort\n");
+        assert!(reader.next()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn messages_with_ranges() -> io::Result<()> {
+        let input = b"\xef\xbb\xbf\nFrom a\n\nfirst\n\nFrom b\n\n>From second\n\nFrom c\n\nthird";
+        let expected = read_all(MboxReader::new(input.as_slice()).trim_leading(true))?;
+        let items = MboxReader::with_capacity(8, input.as_slice())
+            .trim_leading(true)
+            .messages_with_ranges()
+            .collect::<io::Result<Vec<_>>>()?;
+        let (ranges, messages): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+        assert_eq!(messages, expected);
+        // the leading BOM and newline are skipped
+        assert_eq!(ranges[0].start, 4);
+        for (range, next) in ranges.iter().zip(&ranges[1..]) {
+            assert_eq!(range.end, next.start);
+        }
+        assert_eq!(ranges.last().unwrap().end, input.len() as u64);
+        assert_eq!(ranges[1].len(), messages[1].len() as u64 + 1, "one > was removed");
         Ok(())
     }
 }
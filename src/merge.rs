@@ -0,0 +1,77 @@
+// merge.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::MboxReader;
+use std::io::{self, Read};
+
+/// Merges two mailboxes into one sequence of messages in ascending envelope date order. Each side
+/// is read lazily, one message ahead, so the inputs should already be in date order themselves.
+///
+/// When the dates are equal, or either one is missing or can't be parsed, the message from `a`
+/// comes first. This keeps the merge stable but means an undated message is yielded as soon as it
+/// reaches the front of its side. After an error, the iterator ends.
+pub fn merge_by_date<A: Read, B: Read>(
+    mut a: MboxReader<A>,
+    mut b: MboxReader<B>,
+) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+    let mut next_a: Option<(Option<i64>, Vec<u8>)> = None;
+    let mut next_b: Option<(Option<i64>, Vec<u8>)> = None;
+    let mut a_done = false;
+    let mut b_done = false;
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let result = (|| {
+            if next_a.is_none() && !a_done {
+                next_a = dated_message(&mut a)?;
+                a_done = next_a.is_none();
+            }
+            if next_b.is_none() && !b_done {
+                next_b = dated_message(&mut b)?;
+                b_done = next_b.is_none();
+            }
+            let take_b = match (&next_a, &next_b) {
+                (Some((Some(date_a), _)), Some((Some(date_b), _))) => date_b < date_a,
+                (None, Some(_)) => true,
+                _ => false,
+            };
+            let next = if take_b { next_b.take() } else { next_a.take() };
+            Ok(next.map(|(_, msg)| msg))
+        })();
+        failed = result.is_err();
+        result.transpose()
+    })
+}
+
+fn dated_message<R: Read>(
+    reader: &mut MboxReader<R>,
+) -> io::Result<Option<(Option<i64>, Vec<u8>)>> {
+    let mut entry = match reader.next()? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let date = entry.from_line()?.and_then(|line| line.timestamp());
+    let mut msg = Vec::new();
+    entry.read_to_end(&mut msg)?;
+    Ok(Some((date, msg)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge() -> io::Result<()> {
+        let a = b"From a Mon Jan  1 00:00:00 2001\n\n1\nFrom a Wed Jan  3 00:00:00 2001\n\n3\nFrom a Sat Jan  6 00:00:00 2001\n\n6\n";
+        let b = b"From b Tue Jan  2 00:00:00 2001\n\n2\nFrom b Thu Jan  4 00:00:00 2001\n\n4\nFrom b Fri Jan  5 00:00:00 2001\n\n5\nFrom b Sun Jan  7 00:00:00 2001\n\n7\n";
+        let merged = merge_by_date(MboxReader::new(&a[..]), MboxReader::new(&b[..]))
+            .collect::<io::Result<Vec<Vec<u8>>>>()?;
+        let bodies: Vec<u8> = merged.iter().map(|msg| msg[msg.len() - 2]).collect();
+        assert_eq!(bodies, b"1234567");
+        assert!(merged[0].starts_with(b"From a Mon Jan  1"));
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+// mime.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::base64::decode_base64;
+use crate::quoted_printable::QuotedPrintableReader;
+use crate::unfold_headers;
+use memchr::{memchr, memmem};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// A part of a message which is meant to be saved rather than displayed, decoded.
+struct Attachment {
+    filename: Option<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+/// Writes the attachments of a message with `headers` and `body` to files in `dir`, returning
+/// their paths in the order they appear. See [`MboxEntry::extract_attachments`].
+///
+/// [`MboxEntry::extract_attachments`]: crate::MboxEntry::extract_attachments
+pub(crate) fn extract_attachments(
+    headers: &[(Vec<u8>, Vec<u8>)],
+    body: &[u8],
+    dir: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    let mut attachments = Vec::new();
+    collect_attachments(headers, body, &mut attachments)?;
+    attachments
+        .iter()
+        .map(|attachment| write_new_file(dir, attachment))
+        .collect()
+}
+
+/// Walks the part with `headers` and `body`, and any parts nested in it, adding those which are
+/// attachments to `out`.
+fn collect_attachments(
+    headers: &[(Vec<u8>, Vec<u8>)],
+    body: &[u8],
+    out: &mut Vec<Attachment>,
+) -> io::Result<()> {
+    let content_type = header(headers, b"Content-Type").unwrap_or_default();
+    if content_type.len() >= 10 && content_type[..10].eq_ignore_ascii_case(b"multipart/") {
+        if let Some(boundary) = parameter(content_type, b"boundary") {
+            for part in split_parts(body, &boundary) {
+                let (head, body) = split_head(part);
+                collect_attachments(&unfold_headers(head), body, out)?;
+            }
+            return Ok(());
+        }
+    }
+
+    let disposition = header(headers, b"Content-Disposition").unwrap_or_default();
+    let filename = parameter(disposition, b"filename").or_else(|| parameter(content_type, b"name"));
+    let is_attachment =
+        disposition.len() >= 10 && disposition[..10].eq_ignore_ascii_case(b"attachment");
+    if !is_attachment && filename.is_none() {
+        return Ok(());
+    }
+    let encoding = header(headers, b"Content-Transfer-Encoding").unwrap_or_default();
+    let encoding = encoding.trim_ascii();
+    let data = if encoding.eq_ignore_ascii_case(b"base64") {
+        decode_base64(body)
+    } else if encoding.eq_ignore_ascii_case(b"quoted-printable") {
+        let mut data = Vec::with_capacity(body.len());
+        QuotedPrintableReader::new(body, true).read_to_end(&mut data)?;
+        data
+    } else {
+        body.to_vec()
+    };
+    out.push(Attachment { filename, data });
+    Ok(())
+}
+
+/// Returns the value of the first header called `name`, matched case-insensitively.
+fn header<'a>(headers: &'a [(Vec<u8>, Vec<u8>)], name: &[u8]) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_slice())
+}
+
+/// Returns the value of the parameter called `name` in a header value like
+/// `attachment; filename="a.txt"`, without its quotes. Values split or encoded as described in
+/// RFC 2231 aren't reassembled.
+pub(crate) fn parameter(value: &[u8], name: &[u8]) -> Option<Vec<u8>> {
+    value.split(|&b| b == b';').skip(1).find_map(|param| {
+        let param = param.trim_ascii();
+        let eq = param.iter().position(|&b| b == b'=')?;
+        if !param[..eq].trim_ascii().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let value = param[eq + 1..].trim_ascii();
+        let value = value
+            .strip_prefix(b"\"")
+            .and_then(|v| v.strip_suffix(b"\""))
+            .unwrap_or(value);
+        Some(value.to_vec())
+    })
+}
+
+/// Splits a multipart body into its parts, leaving out the preamble and epilogue. The line ending
+/// before each delimiter line belongs to the delimiter (RFC 2046), so it isn't part of the body.
+fn split_parts<'a>(body: &'a [u8], boundary: &[u8]) -> Vec<&'a [u8]> {
+    let mut delimiter = b"--".to_vec();
+    delimiter.extend_from_slice(boundary);
+
+    let mut parts = Vec::new();
+    // the start of the current part, once the first delimiter has been seen
+    let mut part_start: Option<usize> = None;
+    let mut line_start = 0;
+    while line_start < body.len() {
+        let line_end =
+            memchr(b'\n', &body[line_start..]).map_or(body.len(), |i| line_start + i + 1);
+        let line = &body[line_start..line_end];
+        if let Some(rest) = line.strip_prefix(delimiter.as_slice()) {
+            if let Some(start) = part_start {
+                let before = &body[..line_start];
+                let before = before
+                    .strip_suffix(b"\r\n")
+                    .or_else(|| before.strip_suffix(b"\n"))
+                    .unwrap_or(before);
+                parts.push(&body[start..before.len().max(start)]);
+            }
+            if rest.starts_with(b"--") {
+                return parts;
+            }
+            part_start = Some(line_end);
+        }
+        line_start = line_end;
+    }
+    // a missing close delimiter ends the last part at the end of the body
+    if let Some(start) = part_start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Splits a part into its header lines and its body, at the first empty line.
+fn split_head(part: &[u8]) -> (&[u8], &[u8]) {
+    if part.starts_with(b"\n") || part.starts_with(b"\r\n") {
+        let blank = if part[0] == b'\n' { 1 } else { 2 };
+        return (&part[..0], &part[blank..]);
+    }
+    for (ending, len) in [(&b"\r\n\r\n"[..], 4), (b"\n\n", 2)] {
+        if let Some(i) = memmem::find(part, ending) {
+            return (&part[..i + len], &part[i + len..]);
+        }
+    }
+    (part, &part[part.len()..])
+}
+
+/// Returns the name to save an attachment with `filename` under: the filename with any directories
+/// removed, or `attachment` if it has none or what is left couldn't safely be used as a file name
+/// in the directory, e.g. `C:evil.exe`, which Windows takes as relative to a drive.
+fn file_name(filename: Option<&[u8]>) -> String {
+    filename
+        .map(|name| {
+            let name = name.rsplit(|&b| b == b'/' || b == b'\\').next().unwrap();
+            String::from_utf8_lossy(name)
+                .trim_start_matches('.')
+                .to_string()
+        })
+        .filter(|name| {
+            let mut components = Path::new(name).components();
+            !name.contains(|c: char| c == ':' || c.is_control())
+                && matches!(components.next(), Some(Component::Normal(_)))
+                && components.next().is_none()
+        })
+        .unwrap_or_else(|| "attachment".to_string())
+}
+
+/// Writes `attachment` to a new file in `dir`, named by `file_name`. A number is added to the name
+/// if a file by that name already exists, so nothing is overwritten. If writing fails, the file is
+/// removed again, so it doesn't take the name from a later attempt.
+fn write_new_file(dir: &Path, attachment: &Attachment) -> io::Result<PathBuf> {
+    let name = file_name(attachment.filename.as_deref());
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) => name.split_at(dot),
+        None => (name.as_str(), ""),
+    };
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(&name),
+            n => dir.join(format!("{}-{}{}", stem, n, extension)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(&attachment.data) {
+                    drop(file);
+                    let _ = fs::remove_file(&path);
+                    return Err(e);
+                }
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(
+            decode_base64(b"SGVsbG8sIHdv\r\ncmxkIQo="),
+            b"Hello, world!\n"
+        );
+        assert_eq!(decode_base64(b"YQ=="), b"a");
+        assert_eq!(decode_base64(b"YWI="), b"ab");
+        assert_eq!(decode_base64(b"YWJj"), b"abc");
+        assert_eq!(decode_base64(b""), b"");
+    }
+
+    #[test]
+    fn parts() {
+        let body =
+            b"preamble\n--b\nContent-Type: text/plain\n\none\n--b\r\n\r\ntwo\r\n--b--\nepilogue\n";
+        let parts = split_parts(body, b"b");
+        assert_eq!(parts, [&b"Content-Type: text/plain\n\none"[..], b"\r\ntwo"]);
+        assert_eq!(
+            split_head(parts[0]),
+            (&b"Content-Type: text/plain\n\n"[..], &b"one"[..])
+        );
+        assert_eq!(split_head(parts[1]), (&b""[..], &b"two"[..]));
+        assert_eq!(
+            parameter(b"attachment; FileName=\"a b.txt\"; size=3", b"filename"),
+            Some(b"a b.txt".to_vec())
+        );
+    }
+
+    #[test]
+    fn file_names() {
+        let name = |filename: &str| file_name(Some(filename.as_bytes()));
+        assert_eq!(name("report.pdf"), "report.pdf");
+        assert_eq!(name("../../etc/passwd"), "passwd");
+        assert_eq!(name("C:\\Windows\\evil.exe"), "evil.exe");
+        assert_eq!(name(".hidden"), "hidden");
+        assert_eq!(name("C:evil.exe"), "attachment");
+        assert_eq!(name("a\0b"), "attachment");
+        assert_eq!(name(".."), "attachment");
+        assert_eq!(name("dir/"), "attachment");
+        assert_eq!(file_name(None), "attachment");
+    }
+}
@@ -0,0 +1,166 @@
+// quoted_printable.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+// always compiled, since attachments are decoded with it, but only public with the feature
+#![cfg_attr(not(feature = "quoted-printable"), allow(dead_code))]
+
+use std::io::{self, BufRead, Read};
+
+/// Decodes a quoted-printable body (RFC 2045) as it is read, from
+/// [`MboxEntry::quoted_printable_reader`](crate::MboxEntry::quoted_printable_reader).
+///
+/// `=XX` sequences become the byte they encode, soft line breaks (`=` at the end of a line) are
+/// removed along with their line ending, and trailing whitespace on each line is dropped. Other
+/// line endings are kept as they are. An `=` which doesn't start either is passed through, since
+/// real encoders aren't always careful.
+pub struct QuotedPrintableReader<R> {
+    inner: R,
+    /// When clear, the body isn't encoded and is passed through unchanged.
+    decode: bool,
+    /// The current line, as read.
+    line: Vec<u8>,
+    /// The current line, decoded, and how much of it has been returned.
+    decoded: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> QuotedPrintableReader<R> {
+    pub(crate) fn new(inner: R, decode: bool) -> Self {
+        QuotedPrintableReader {
+            inner,
+            decode,
+            line: Vec::new(),
+            decoded: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn decode_line(&mut self) {
+        self.decoded.clear();
+        self.pos = 0;
+        let (content, ending) = split_line_ending(&self.line);
+        let content = trim_end_whitespace(content);
+        let (content, soft_break) = match content.strip_suffix(b"=") {
+            Some(content) => (content, true),
+            None => (content, false),
+        };
+        let mut i = 0;
+        while i < content.len() {
+            if content[i] == b'=' {
+                if let (Some(high), Some(low)) = (
+                    content.get(i + 1).and_then(hex_value),
+                    content.get(i + 2).and_then(hex_value),
+                ) {
+                    self.decoded.push(high << 4 | low);
+                    i += 3;
+                    continue;
+                }
+            }
+            self.decoded.push(content[i]);
+            i += 1;
+        }
+        if !soft_break {
+            self.decoded.extend_from_slice(ending);
+        }
+    }
+}
+
+impl<R: BufRead> Read for QuotedPrintableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.decode {
+            return self.inner.read(buf);
+        }
+        while self.pos == self.decoded.len() {
+            self.line.clear();
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            self.decode_line();
+        }
+        let copied = (self.decoded.len() - self.pos).min(buf.len());
+        buf[..copied].copy_from_slice(&self.decoded[self.pos..self.pos + copied]);
+        self.pos += copied;
+        Ok(copied)
+    }
+}
+
+fn split_line_ending(line: &[u8]) -> (&[u8], &[u8]) {
+    let ending = if line.ends_with(b"\r\n") {
+        2
+    } else if line.ends_with(b"\n") {
+        1
+    } else {
+        0
+    };
+    line.split_at(line.len() - ending)
+}
+
+fn trim_end_whitespace(bytes: &[u8]) -> &[u8] {
+    let end = bytes
+        .iter()
+        .rposition(|&b| b != b' ' && b != b'\t')
+        .map_or(0, |i| i + 1);
+    &bytes[..end]
+}
+
+fn hex_value(digit: &u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "quoted-printable"))]
+mod test {
+    use crate::MboxReader;
+    use std::io::{self, Read};
+
+    #[test]
+    fn quoted_printable_reader() -> io::Result<()> {
+        let input = concat!(
+            "From a\n",
+            "Content-Transfer-Encoding: Quoted-Printable\n",
+            "\n",
+            "Caf=C3=A9 au lait, 50=25 off. This line is long enough that it has to be soft=\n",
+            " broken.   \n",
+            "a=3Db =ZZ =\r\n",
+            "done=\n",
+            "From b\n",
+            "Subject: not encoded\n",
+            "\n",
+            "=C3=A9=\n",
+        );
+        let mut reader = MboxReader::new(input.as_bytes());
+
+        let mut body = Vec::new();
+        let mut decoded = reader.next()?.unwrap().quoted_printable_reader()?;
+        // small reads, so lines are returned in pieces
+        let mut buf = [0; 3];
+        loop {
+            match decoded.read(&mut buf)? {
+                0 => break,
+                n => body.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "Café au lait, 50% off. This line is long enough that it has to be soft broken.\na=b =ZZ done"
+        );
+
+        let mut body = Vec::new();
+        reader
+            .next()?
+            .unwrap()
+            .quoted_printable_reader()?
+            .read_to_end(&mut body)?;
+        assert_eq!(body, b"=C3=A9=\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+// scanner.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::{find_boundary, MAGIC_WORD};
+use memchr::memmem;
+
+/// Finds message boundaries in an mbox stream which is pushed to it in pieces, for callers who
+/// get their data some other way than from a `Read`, e.g. from an event loop. This is the search
+/// [`MboxReader`] does, without any buffering of messages or unescaping of `From ` lines.
+///
+/// Boundaries are reported as offsets in the stream, counting every byte fed so far, of the
+/// `From ` which starts each message. As for [`find_boundary`], a `From ` at the very start of
+/// the stream isn't a boundary, since nothing comes before it.
+///
+/// [`MboxReader`]: crate::MboxReader
+#[derive(Debug, Default, Clone)]
+pub struct Scanner {
+    /// The last bytes fed, in case a magic word starts in them.
+    tail: Vec<u8>,
+    /// How many bytes have been fed.
+    offset: u64,
+    boundaries: Vec<u64>,
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scans the next piece of the stream. A boundary split between this piece and the ones
+    /// before or after it is found too.
+    pub fn feed(&mut self, data: &[u8]) {
+        let tail_len = self.tail.len();
+        let tail_start = self.offset - tail_len as u64;
+        self.tail
+            .extend_from_slice(&data[..data.len().min(MAGIC_WORD.len() - 1)]);
+        // at most one can start in the tail; one wholly in `data` is found below
+        if let Some(i) = find_boundary(&self.tail).filter(|&i| i <= tail_len) {
+            self.boundaries.push(tail_start + i as u64);
+        }
+        self.tail.truncate(tail_len);
+
+        let boundaries = memmem::find_iter(data, &MAGIC_WORD).map(|i| self.offset + i as u64 + 1);
+        self.boundaries.extend(boundaries);
+
+        self.tail.extend_from_slice(data);
+        let keep = self.tail.len().min(MAGIC_WORD.len() - 1);
+        self.tail.drain(..self.tail.len() - keep);
+        self.offset += data.len() as u64;
+    }
+
+    /// The offsets of the boundaries found so far, in order.
+    pub fn boundaries(&self) -> &[u64] {
+        &self.boundaries
+    }
+
+    /// Returns the boundaries found since the last call, so they don't pile up over a long
+    /// stream.
+    pub fn take_boundaries(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.boundaries)
+    }
+
+    /// How many bytes have been fed.
+    pub fn position(&self) -> u64 {
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn incremental() {
+        let input = b"From a\nbody\nFrom b\n\nFrom\nFrom c\n>From d\nFrom e";
+        let expected = [12, 25, 40];
+        for chunk_size in 1..=input.len() {
+            let mut scanner = Scanner::new();
+            for chunk in input.chunks(chunk_size) {
+                scanner.feed(chunk);
+            }
+            assert_eq!(scanner.boundaries(), expected, "chunks of {}", chunk_size);
+            assert_eq!(scanner.position(), input.len() as u64);
+        }
+
+        let mut scanner = Scanner::new();
+        scanner.feed(&input[..18]);
+        assert_eq!(scanner.take_boundaries(), [12]);
+        scanner.feed(&[]);
+        scanner.feed(&input[18..]);
+        assert_eq!(scanner.take_boundaries(), [25, 40]);
+        assert!(scanner.boundaries().is_empty());
+    }
+}
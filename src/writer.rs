@@ -0,0 +1,595 @@
+// writer.rs
+// mbox-indexer
+// Copyright 2022 Andrew Morrow. All rights reserved.
+
+use crate::headers::trim_line_ending;
+use crate::{fold_header, unfold_headers, MboxReader};
+use memchr::memchr;
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The conventions for escaping body lines which would otherwise look like the start of a message.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// Lines matching `>*From ` get one more `>` when written, and lose one when read. This is
+    /// reversible for every body.
+    #[default]
+    Mboxrd,
+    /// Lines starting with `From ` become `>From ` when written, and `>From ` becomes `From ` when
+    /// read. A body which already contained `>From ` doesn't survive the round trip.
+    Mboxo,
+    /// Nothing is escaped. A body containing a `From ` line will be split when read back.
+    Raw,
+}
+
+impl Dialect {
+    /// Whether `line` (starting at the beginning of a line) needs a `>` prepended when written.
+    fn needs_escape(self, line: &[u8]) -> bool {
+        match self {
+            Dialect::Mboxrd => {
+                let quotes = line.iter().take_while(|&&b| b == b'>').count();
+                line[quotes..].starts_with(b"From ")
+            }
+            Dialect::Mboxo => line.starts_with(b"From "),
+            Dialect::Raw => false,
+        }
+    }
+}
+
+/// Writes messages in mbox format, escaping `From ` lines in their bodies according to a
+/// [`Dialect`].
+pub struct MboxWriter<W> {
+    inner: W,
+    dialect: Dialect,
+    /// Header lines longer than this are folded, if set.
+    fold_width: Option<usize>,
+}
+
+impl<W: Write> MboxWriter<W> {
+    pub fn new_mboxrd(inner: W) -> Self {
+        Self::with_dialect(inner, Dialect::Mboxrd)
+    }
+
+    pub fn new_mboxo(inner: W) -> Self {
+        Self::with_dialect(inner, Dialect::Mboxo)
+    }
+
+    /// Creates a writer which doesn't escape anything.
+    pub fn new_raw(inner: W) -> Self {
+        Self::with_dialect(inner, Dialect::Raw)
+    }
+
+    pub fn with_dialect(inner: W, dialect: Dialect) -> Self {
+        MboxWriter {
+            inner,
+            dialect,
+            fold_width: None,
+        }
+    }
+
+    /// Folds header lines longer than `width` bytes when writing messages, as [`fold_header`] does:
+    /// only before whitespace which is already there, so a header reads back unfolded exactly as
+    /// it was, and a line with no whitespace to fold at stays long. Headers which fit are written
+    /// as they are. The default is to write headers unchanged.
+    pub fn fold_headers(mut self, width: usize) -> Self {
+        self.fold_width = Some(width);
+        self
+    }
+
+    /// Writes one message: the envelope line `from_line` (which must start with `From `, and may
+    /// or may not end with a newline), then `message` (its headers and body) escaped, then a blank
+    /// line to separate it from the next message. A newline is added to `message` if it doesn't end
+    /// with one.
+    pub fn append_message(&mut self, from_line: &[u8], message: &[u8]) -> io::Result<()> {
+        if !from_line.starts_with(b"From ") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "envelope line must start with \"From \"",
+            ));
+        }
+        let from_line = from_line.strip_suffix(b"\n").unwrap_or(from_line);
+        if from_line.contains(&b'\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "envelope line must be a single line",
+            ));
+        }
+        self.inner.write_all(from_line)?;
+        self.inner.write_all(b"\n")?;
+        let message = match self.fold_width {
+            Some(width) => fold_long_headers(message, width),
+            None => Cow::Borrowed(message),
+        };
+        for line in message.split_inclusive(|&b| b == b'\n') {
+            if self.dialect.needs_escape(line) {
+                self.inner.write_all(b">")?;
+            }
+            self.inner.write_all(line)?;
+        }
+        if !message.is_empty() && !message.ends_with(b"\n") {
+            self.inner.write_all(b"\n")?;
+        }
+        self.inner.write_all(b"\n")
+    }
+
+    /// Writes a message as read by an `MboxReader`, envelope line and all. If `has_separator`, a
+    /// blank line at the end is taken to be the reader's copy of the separator and not written
+    /// twice. Returns an `InvalidData` error if the message doesn't start with a `From ` line.
+    pub(crate) fn append_read_message(
+        &mut self,
+        message: &[u8],
+        has_separator: bool,
+    ) -> io::Result<()> {
+        let envelope_len = memchr(b'\n', message).map_or(message.len(), |i| i + 1);
+        let (from_line, mut rest) = message.split_at(envelope_len);
+        if !from_line.starts_with(b"From ") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message has no envelope line",
+            ));
+        }
+        if has_separator {
+            rest = &rest[..rest.len() - separator_len(rest)];
+        }
+        self.append_message(from_line, rest)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl MboxWriter<File> {
+    /// Appends one message to the mbox file at `path`, creating it if necessary, as
+    /// [`append_message`](MboxWriter::append_message) would write it with `dialect`. If the file
+    /// doesn't already end with a blank line, a line ending or two is written first so the new
+    /// message is separated from the last one.
+    ///
+    /// The file is locked for the duration (see [`File::lock`]), so messages appended at the same
+    /// time by cooperating processes don't get mixed up. The message is written all at once, and
+    /// the file is truncated back to its old length if that fails.
+    pub fn append_to_file(
+        path: &Path,
+        dialect: Dialect,
+        from_line: &[u8],
+        message: &[u8],
+    ) -> io::Result<()> {
+        let mut writer = MboxWriter::with_dialect(Vec::new(), dialect);
+        writer.append_message(from_line, message)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        file.lock()?;
+
+        let len = file.seek(SeekFrom::End(0))?;
+        let mut last = Vec::with_capacity(3);
+        file.seek(SeekFrom::Start(len.saturating_sub(3)))?;
+        (&file).read_to_end(&mut last)?;
+        let separator: &[u8] = if last.len() as u64 == len && trim_line_ending(&last).is_empty()
+            || last.ends_with(b"\n\n")
+            || last.ends_with(b"\n\r\n")
+        {
+            b""
+        } else if last.ends_with(b"\n") {
+            b"\n"
+        } else {
+            b"\n\n"
+        };
+        let mut bytes = separator.to_vec();
+        bytes.append(&mut writer.inner);
+        if let Err(e) = file.write_all(&bytes).and_then(|()| file.sync_data()) {
+            let _ = file.set_len(len);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Copies every message from `reader` to `writer`, passing each to `transform` first, e.g. to
+/// scrub it. Messages are given to `transform` whole, envelope line included, but without the
+/// blank line which separates them from the next message, since the writer adds its own. They may
+/// be changed however `transform` likes as long as they still start with a `From ` line, or an
+/// `InvalidData` error is returned. Body lines are escaped as the writer's dialect requires.
+pub fn rewrite<R: Read, W: Write>(
+    mut reader: MboxReader<R>,
+    writer: &mut MboxWriter<W>,
+    mut transform: impl FnMut(&mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    let has_separator = reader.inner.keep_separator_line;
+    while let Some(mut message) = reader.next_message()? {
+        if has_separator {
+            message.truncate(message.len() - separator_len(&message));
+        }
+        transform(&mut message)?;
+        writer.append_read_message(&message, false)?;
+    }
+    writer.flush()
+}
+
+/// Copies every message from `reader` to `writer` for files where messages have run together, so
+/// that each is followed by exactly one blank line: any blank lines at the end of a message are
+/// replaced by a single one, and one is added where it was missing. If `normalize_line_endings`,
+/// `\r\n` line endings are written as `\n` too. Body lines are escaped as the writer's dialect
+/// requires, so the result reads back cleanly with an `MboxReader` of that dialect. As for
+/// [`rewrite`], anything before the first `From ` line is an `InvalidData` error.
+pub fn repair<R: Read, W: Write>(
+    reader: MboxReader<R>,
+    writer: &mut MboxWriter<W>,
+    normalize_line_endings: bool,
+) -> io::Result<()> {
+    rewrite(reader.trim_trailing_blank_lines(true), writer, |message| {
+        if normalize_line_endings {
+            let mut normalized = Vec::with_capacity(message.len());
+            for line in message.split_inclusive(|&b| b == b'\n') {
+                match line.strip_suffix(b"\r\n") {
+                    Some(line) => {
+                        normalized.extend_from_slice(line);
+                        normalized.push(b'\n');
+                    }
+                    None => normalized.extend_from_slice(line),
+                }
+            }
+            *message = normalized;
+        }
+        Ok(())
+    })
+}
+
+/// Deletes the message starting at `offset` from the mbox file at `path`, which must be where the
+/// file's index (see [`MboxReader::build_index_detailed`]) says a message starts, or an
+/// `InvalidInput` error is returned. The rest of the file is copied to a new file next to it,
+/// which then replaces it, so the file is never seen half rewritten and nothing is lost if
+/// copying fails. The message goes along with the blank line after it, so the messages around
+/// it stay separated by one.
+pub fn remove_message(path: &Path, offset: u64) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let index = MboxReader::new(&mut file).build_index_detailed()?;
+    let range = match index.entries().iter().find(|entry| entry.offset == offset) {
+        Some(entry) => entry.range(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no message starts at that offset",
+            ))
+        }
+    };
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    let copied = (|| {
+        let mut temp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut (&file).take(range.start), &mut temp)?;
+        file.seek(SeekFrom::Start(range.end))?;
+        io::copy(&mut file, &mut temp)?;
+        temp.set_permissions(file.metadata()?.permissions())?;
+        temp.sync_all()
+    })();
+    match copied.and_then(|()| fs::rename(&temp_path, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Refolds the headers of `message` which have a line longer than `width`, keeping their line
+/// endings. Everything from the first empty line on is the body and left alone.
+fn fold_long_headers(message: &[u8], width: usize) -> Cow<'_, [u8]> {
+    let body_start = message
+        .split_inclusive(|&b| b == b'\n')
+        .take_while(|line| !trim_line_ending(line).is_empty())
+        .map(<[u8]>::len)
+        .sum();
+    let (head, body) = message.split_at(body_start);
+    if !head
+        .split_inclusive(|&b| b == b'\n')
+        .any(|line| trim_line_ending(line).len() > width)
+    {
+        return Cow::Borrowed(message);
+    }
+
+    let mut folded = Vec::with_capacity(message.len() + message.len() / width.max(1));
+    let mut lines = head.split_inclusive(|&b| b == b'\n').peekable();
+    while let Some(first) = lines.next() {
+        // a header and its continuation lines
+        let mut header = first.to_vec();
+        let mut too_long = trim_line_ending(first).len() > width;
+        while let Some(line) =
+            lines.next_if(|line| line.starts_with(b" ") || line.starts_with(b"\t"))
+        {
+            header.extend_from_slice(line);
+            too_long |= trim_line_ending(line).len() > width;
+        }
+        match unfold_headers(&header).as_slice() {
+            [(name, value)] if too_long => {
+                let line_ending: &[u8] = if first.ends_with(b"\r\n") {
+                    b"\r\n"
+                } else {
+                    b"\n"
+                };
+                let refolded = fold_header(name, value, width);
+                for line in refolded.split_inclusive(|&b| b == b'\n') {
+                    folded.extend_from_slice(trim_line_ending(line));
+                    folded.extend_from_slice(line_ending);
+                }
+            }
+            _ => folded.extend_from_slice(&header),
+        }
+    }
+    folded.extend_from_slice(body);
+    Cow::Owned(folded)
+}
+
+/// Returns the length of the blank line at the end of `message`, if there is one.
+fn separator_len(message: &[u8]) -> usize {
+    if message.ends_with(b"\r\n\r\n") {
+        2
+    } else if message.ends_with(b"\n\n") {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writes `messages` in `dialect`, reads them back, and returns them with the envelope line
+    /// and separating blank line removed.
+    fn round_trip(dialect: Dialect, messages: &[&[u8]]) -> io::Result<Vec<Vec<u8>>> {
+        let mut writer = MboxWriter::with_dialect(Vec::new(), dialect);
+        for message in messages {
+            writer.append_message(b"From sender Mon Jan  1 00:00:00 2001", message)?;
+        }
+        let written = writer.into_inner();
+
+        let mut reader = MboxReader::new(written.as_slice()).dialect(dialect);
+        let mut read = Vec::new();
+        while let Some(msg) = reader.next_message()? {
+            assert!(msg.starts_with(b"From sender Mon Jan  1 00:00:00 2001\n"));
+            assert!(msg.ends_with(b"\n\n"));
+            read.push(msg[37..msg.len() - 1].to_vec());
+        }
+        Ok(read)
+    }
+
+    #[test]
+    fn mboxrd_round_trip() -> io::Result<()> {
+        let messages: [&[u8]; 2] = [
+            b"Subject: one\n\nFrom the start\n>From quoted\n>>From deeper\nFromage\n",
+            b"Subject: two\n\n>>>From very deep\nFrom again\n",
+        ];
+        assert_eq!(round_trip(Dialect::Mboxrd, &messages)?, messages);
+
+        let mut writer = MboxWriter::new_mboxrd(Vec::new());
+        writer.append_message(b"From a\n", b"\n>From x\nFrom y")?;
+        assert_eq!(writer.get_ref(), b"From a\n\n>>From x\n>From y\n\n");
+        Ok(())
+    }
+
+    #[test]
+    fn mboxo_round_trip() -> io::Result<()> {
+        let messages: [&[u8]; 2] = [
+            b"Subject: one\n\nFrom the start\nFromage\n",
+            b"Subject: two\n\n>>From isn't touched\nFrom again\n",
+        ];
+        assert_eq!(round_trip(Dialect::Mboxo, &messages)?, messages);
+
+        let mut writer = MboxWriter::new_mboxo(Vec::new());
+        writer.append_message(b"From a\n", b"\n>From x\nFrom y\n")?;
+        assert_eq!(writer.get_ref(), b"From a\n\n>From x\n>From y\n\n");
+        Ok(())
+    }
+
+    #[test]
+    fn raw_round_trip() -> io::Result<()> {
+        let messages: [&[u8]; 2] = [
+            b"Subject: one\n\n>From stays\n",
+            b"Subject: two\n\n>>From stays too\n",
+        ];
+        assert_eq!(round_trip(Dialect::Raw, &messages)?, messages);
+
+        let mut writer = MboxWriter::new_raw(Vec::new());
+        writer.append_message(b"From a\n", b"\nFrom y\n")?;
+        assert_eq!(writer.get_ref(), b"From a\n\nFrom y\n\n");
+        Ok(())
+    }
+
+    #[test]
+    fn bad_envelope() {
+        let mut writer = MboxWriter::new_mboxrd(Vec::new());
+        let err = writer.append_message(b"Subject: hi", b"").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = writer.append_message(b"From a\nFrom b", b"").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn repair() -> io::Result<()> {
+        let input = b"From a\r\nSubject: one\r\n\r\nno blank line\r\nFrom b\n\ntoo many\n\n\n\nFrom c\n\n>From quoted\nno newline";
+        let mut writer = MboxWriter::new_mboxrd(Vec::new());
+        super::repair(MboxReader::new(input.as_slice()), &mut writer, true)?;
+        let written = writer.into_inner();
+        assert_eq!(
+            written,
+            b"From a\nSubject: one\n\nno blank line\n\nFrom b\n\ntoo many\n\nFrom c\n\n>From quoted\nno newline\n\n"
+        );
+        let messages = MboxReader::new(written.as_slice())
+            .keep_separator_line(false)
+            .take_messages(usize::MAX)
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            messages,
+            [
+                &b"From a\nSubject: one\n\nno blank line\n"[..],
+                b"From b\n\ntoo many\n",
+                b"From c\n\nFrom quoted\nno newline\n\n",
+            ]
+        );
+
+        let mut writer = MboxWriter::new_mboxrd(Vec::new());
+        super::repair(MboxReader::new(input.as_slice()), &mut writer, false)?;
+        assert!(writer
+            .get_ref()
+            .starts_with(b"From a\r\nSubject: one\r\n\r\nno blank line\r\n\nFrom b\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_message() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mbox-indexer-remove-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("mbox");
+        let input = b"From a\n\nfirst\n\nFrom b\n\n>From second\n\nFrom c\n\nthird\n";
+        fs::write(&path, input)?;
+
+        super::remove_message(&path, 15)?;
+        assert_eq!(fs::read(&path)?, b"From a\n\nfirst\n\nFrom c\n\nthird\n");
+        let messages = MboxReader::new(File::open(&path)?)
+            .take_messages(usize::MAX)
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            messages,
+            [&b"From a\n\nfirst\n\n"[..], b"From c\n\nthird\n"]
+        );
+
+        let error = super::remove_message(&path, 16).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn append_to_file() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mbox-indexer-append-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("mbox");
+        fs::write(&path, b"From a\n\nfirst\n\nFrom b\n\nno blank line")?;
+
+        MboxWriter::append_to_file(&path, Dialect::Mboxrd, b"From c", b"\nFrom the top\n")?;
+        MboxWriter::append_to_file(&path, Dialect::Mboxrd, b"From d\n", b"\nlast")?;
+        let messages = MboxReader::new(File::open(&path)?)
+            .take_messages(usize::MAX)
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            messages,
+            [
+                &b"From a\n\nfirst\n\n"[..],
+                b"From b\n\nno blank line\n\n",
+                b"From c\n\nFrom the top\n\n",
+                b"From d\n\nlast\n\n",
+            ]
+        );
+
+        // a new file gets no separator in front
+        let new_path = dir.join("new");
+        MboxWriter::append_to_file(&new_path, Dialect::Raw, b"From e", b"\nbody\n")?;
+        assert_eq!(fs::read(&new_path)?, b"From e\n\nbody\n\n");
+        fs::write(&new_path, b"From f\r\n\r\nbody\r\n\r\n")?;
+        MboxWriter::append_to_file(&new_path, Dialect::Raw, b"From g", b"\nbody\n")?;
+        assert_eq!(
+            fs::read(&new_path)?,
+            b"From f\r\n\r\nbody\r\n\r\nFrom g\n\nbody\n\n"
+        );
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn fold_headers() -> io::Result<()> {
+        let references = (0..20)
+            .map(|i| format!("<message{}@example.com>", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let message = format!(
+            "Subject: short\r\nReferences: {}\r\nX-Token: {}\r\n\r\n{}\r\n",
+            references,
+            "x".repeat(100),
+            "a body line which is long but isn't a header, so it isn't folded at all"
+        );
+        let mut writer = MboxWriter::new_mboxrd(Vec::new()).fold_headers(78);
+        writer.append_message(b"From a", message.as_bytes())?;
+        let written = writer.into_inner();
+
+        let lines: Vec<&[u8]> = written.split_inclusive(|&b| b == b'\n').collect();
+        assert_eq!(lines[1], b"Subject: short\r\n");
+        assert!(lines[2].len() <= 80 && lines[3].starts_with(b" <message"));
+        assert!(lines.iter().all(|line| line.ends_with(b"\n")));
+        assert!(lines
+            .iter()
+            .filter(|line| line.len() > 80)
+            .all(|line| line.starts_with(b"X-Token: ") || line.starts_with(b"a body line")));
+
+        let read = MboxReader::new(written.as_slice()).next_message()?.unwrap();
+        let headers = unfold_headers(&read);
+        assert_eq!(
+            headers[1],
+            (b"References".to_vec(), references.into_bytes())
+        );
+        assert_eq!(headers[2].1, "x".repeat(100).into_bytes());
+        assert!(read.ends_with(b"so it isn't folded at all\r\n\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite() -> io::Result<()> {
+        let input = b"From a\nSubject: hello\n\n>From the body\n\nFrom b\nX-Other: 1\nSubject: second one\n\nsubject: not a header\n";
+        let mut writer = MboxWriter::new_mboxrd(Vec::new());
+        super::rewrite(MboxReader::new(input.as_slice()), &mut writer, |message| {
+            let mut rewritten = Vec::with_capacity(message.len());
+            let mut in_headers = true;
+            for line in message.split_inclusive(|&b| b == b'\n') {
+                if in_headers && line.starts_with(b"Subject:") {
+                    rewritten.extend_from_slice(&line.to_ascii_uppercase());
+                } else {
+                    rewritten.extend_from_slice(line);
+                }
+                in_headers &= line != b"\n";
+            }
+            // the length changes
+            rewritten.extend_from_slice(b"added line\n");
+            *message = rewritten;
+            Ok(())
+        })?;
+        let written = writer.into_inner();
+
+        let messages = crate::MboxReader::new(written.as_slice())
+            .take_messages(usize::MAX)
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(
+            messages,
+            [
+                &b"From a\nSUBJECT: HELLO\n\nFrom the body\nadded line\n\n"[..],
+                b"From b\nX-Other: 1\nSUBJECT: SECOND ONE\n\nsubject: not a header\nadded line\n\n",
+            ]
+        );
+
+        let mut writer = MboxWriter::new_mboxrd(Vec::new());
+        let error = super::rewrite(MboxReader::new(input.as_slice()), &mut writer, |message| {
+            message.drain(..5);
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}